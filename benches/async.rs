@@ -0,0 +1,52 @@
+//! Benchmarks logging from 1, 4, 16, and 32 threads at once in asynchronous mode, writing to
+//! `/dev/null` so that contention on the buffer dominates.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mylog::{Builder, Destination, LogFile};
+use std::time::{Duration, Instant};
+
+/// Entries logged per thread per iteration.
+const ENTRIES: u64 = 1_000;
+
+fn log_threads(c: &mut Criterion) {
+    Builder::new()
+        .spec("info")
+        .destination(Destination::File(LogFile::new("/dev/null")))
+        .async_mode(true)
+        .build()
+        .install()
+        .unwrap();
+    let mut g = c.benchmark_group("log_threads");
+    for threads in [1, 4, 16, 32] {
+        g.throughput(criterion::Throughput::Elements(threads * ENTRIES));
+        g.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        std::thread::scope(|s| {
+                            for t in 0..threads {
+                                s.spawn(move || {
+                                    for i in 0..ENTRIES {
+                                        log::info!("thread {} entry {}", t, i);
+                                    }
+                                });
+                            }
+                        });
+                        log::logger().flush();
+                        total += start.elapsed();
+                    }
+                    total
+                })
+            },
+        );
+    }
+    g.finish();
+    mylog::shutdown();
+}
+
+criterion_group!(benches, log_threads);
+criterion_main!(benches);
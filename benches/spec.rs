@@ -0,0 +1,51 @@
+//! Benchmarks `Specification::get_level` with 1, 10, and 100 directives (plus a default),
+//! comparing the trie against the linear scan still used by `legacy_prefix_matching(true)`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mylog::Specification;
+use std::hint::black_box;
+
+/// Returns a spec with `n` directives `crateI::moduleI=debug`, plus a default of `info`.
+fn spec(n: usize) -> String {
+    let mut spec = "info".to_owned();
+    for i in 0..n {
+        spec.push_str(&format!(",crate{}::module{}=debug", i, i));
+    }
+    spec
+}
+
+/// Kinds of lookup: matching only the default, matching a directive exactly, and matching a
+/// directive as a prefix of a deep module.
+const CASES: [&str; 3] = ["miss", "exact", "deep"];
+
+/// Returns the target to look up in `spec(n)` for the given case.
+fn target(case: &str, n: usize) -> String {
+    let last = n - 1;
+    match case {
+        "miss" => "my_crate::some::module".to_owned(),
+        "exact" => format!("crate{}::module{}", last, last),
+        _ => format!("crate{}::module{}::a::b::c::d::e::f", last, last),
+    }
+}
+
+fn get_level(c: &mut Criterion) {
+    for case in CASES {
+        let mut g = c.benchmark_group(format!("get_level/{}", case));
+        for n in [1, 10, 100] {
+            let trie = Specification::parse(&spec(n)).unwrap();
+            let linear = trie.clone().legacy_prefix_matching(true);
+            let target = target(case, n);
+            assert_eq!(linear.get_level(&target), trie.get_level(&target));
+            g.bench_with_input(BenchmarkId::new("trie", n), &trie, |b, spec| {
+                b.iter(|| spec.get_level(black_box(&target)))
+            });
+            g.bench_with_input(BenchmarkId::new("linear", n), &linear, |b, spec| {
+                b.iter(|| spec.get_level(black_box(&target)))
+            });
+        }
+        g.finish();
+    }
+}
+
+criterion_group!(benches, get_level);
+criterion_main!(benches);
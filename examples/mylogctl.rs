@@ -0,0 +1,32 @@
+//! Sends commands to a process's control socket; see `mylog::Handle::listen_control`.
+//!
+//! Usage: `mylogctl SOCKET COMMAND...`, e.g.
+//! `mylogctl /run/foo/log.sock set-spec info,foo::db=debug`.
+
+use std::io::{BufRead as _, BufReader, Write as _};
+use std::os::unix::net::UnixStream;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("usage: mylogctl SOCKET get-spec|set-spec SPEC|flush|stats");
+        std::process::exit(2);
+    }
+    let conn = UnixStream::connect(&args[0]).unwrap_or_else(|e| {
+        eprintln!("unable to connect to {}: {}", &args[0], e);
+        std::process::exit(1);
+    });
+    let cmd = args[1..].join(" ");
+    (&conn).write_all(cmd.as_bytes()).unwrap();
+    (&conn).write_all(b"\n").unwrap();
+    conn.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut ok = true;
+    for line in BufReader::new(&conn).lines() {
+        let line = line.unwrap();
+        ok &= line.starts_with("ok");
+        println!("{}", line);
+    }
+    if !ok {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,262 @@
+//! Buffers of entries waiting to be written by the asynchronous logger thread.
+//!
+//! To reduce contention between logging threads, the buffer is split into shards, each used by
+//! a subset of threads. Each entry gets a global sequence number (assigned under its shard's
+//! lock), so the logger thread can merge the shards back into the order entries were logged.
+
+use log::Level;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// The maximum number of shards.
+const MAX_SHARDS: usize = 16;
+
+/// Entries waiting to be written by the asynchronous logger thread.
+pub(crate) struct AsyncBuf {
+    /// The concatenated entries.
+    pub(crate) bytes: Vec<u8>,
+
+    /// The end offset within `bytes` of each entry, needed for datagram-based destinations.
+    pub(crate) ends: Vec<usize>,
+
+    /// A bitmask of the sinks each entry is destined for.
+    pub(crate) masks: Vec<u64>,
+
+    /// The level of each entry, for `Overflow::DropLowestSeverity`.
+    pub(crate) levels: Vec<Level>,
+
+    /// The sequence number of each entry, in increasing order.
+    pub(crate) seqs: Vec<u64>,
+}
+
+impl AsyncBuf {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        AsyncBuf {
+            bytes: Vec::with_capacity(capacity),
+            ends: Vec::new(),
+            masks: Vec::new(),
+            levels: Vec::new(),
+            seqs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bytes.clear();
+        self.ends.clear();
+        self.masks.clear();
+        self.levels.clear();
+        self.seqs.clear();
+    }
+
+    pub(crate) fn push(&mut self, entry: &[u8], mask: u64, level: Level, seq: u64) {
+        self.bytes.extend_from_slice(entry);
+        self.ends.push(self.bytes.len());
+        self.masks.push(mask);
+        self.levels.push(level);
+        self.seqs.push(seq);
+    }
+
+    /// Returns the `i`th entry.
+    fn entry(&self, i: usize) -> &[u8] {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        &self.bytes[start..self.ends[i]]
+    }
+
+    /// Removes entries less severe than `level` until at most `max_len` bytes remain: the
+    /// least severe first, and within a level, the oldest first. If that's impossible, removes
+    /// nothing. Returns the number of entries removed.
+    pub(crate) fn evict_less_severe(&mut self, level: Level, max_len: usize) -> usize {
+        let mut evict = vec![false; self.ends.len()];
+        let mut remaining = self.bytes.len();
+        let mut victim = Level::Trace;
+        while remaining > max_len && victim > level {
+            for (i, e) in evict.iter_mut().enumerate() {
+                if remaining <= max_len {
+                    break;
+                }
+                if self.levels[i] == victim {
+                    *e = true;
+                    remaining -= self.entry(i).len();
+                }
+            }
+            victim = match victim {
+                Level::Trace => Level::Debug,
+                Level::Debug => Level::Info,
+                Level::Info => Level::Warn,
+                _ => Level::Error,
+            };
+        }
+        if remaining > max_len {
+            return 0;
+        }
+        let mut start = 0;
+        let mut len = 0;
+        let mut kept = 0;
+        for (i, &e) in evict.iter().enumerate() {
+            let end = self.ends[i];
+            if !e {
+                self.bytes.copy_within(start..end, len);
+                len += end - start;
+                self.ends[kept] = len;
+                self.masks[kept] = self.masks[i];
+                self.levels[kept] = self.levels[i];
+                self.seqs[kept] = self.seqs[i];
+                kept += 1;
+            }
+            start = end;
+        }
+        let removed = self.ends.len() - kept;
+        self.bytes.truncate(len);
+        self.ends.truncate(kept);
+        self.masks.truncate(kept);
+        self.levels.truncate(kept);
+        self.seqs.truncate(kept);
+        removed
+    }
+
+    /// Appends the entries of `bufs`, merged in sequence order.
+    pub(crate) fn merge_from(&mut self, bufs: &[AsyncBuf]) {
+        let mut next = vec![0; bufs.len()];
+        loop {
+            // There are few shards, so a linear scan for the lowest sequence number is fine.
+            let mut lowest: Option<(usize, u64)> = None;
+            for (i, b) in bufs.iter().enumerate() {
+                if let Some(&seq) = b.seqs.get(next[i]) {
+                    if lowest.map_or(true, |(_, l)| seq < l) {
+                        lowest = Some((i, seq));
+                    }
+                }
+            }
+            let (i, seq) = match lowest {
+                Some(l) => l,
+                None => return,
+            };
+            let b = &bufs[i];
+            let j = next[i];
+            self.push(b.entry(j), b.masks[j], b.levels[j], seq);
+            next[i] += 1;
+        }
+    }
+
+    /// Iterates through the individual entries.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &[u8]> {
+        let mut start = 0;
+        self.ends.iter().map(move |&end| {
+            let entry = &self.bytes[start..end];
+            start = end;
+            entry
+        })
+    }
+}
+
+/// A shard of the asynchronous buffer.
+pub(crate) struct Shard {
+    pub(crate) inner: Mutex<ShardInner>,
+
+    /// Notified when the logger thread empties this shard.
+    pub(crate) wake_producers: Condvar,
+}
+
+pub(crate) struct ShardInner {
+    pub(crate) buf: AsyncBuf,
+
+    /// The number of entries dropped due to `Overflow` since the logger thread last reported.
+    pub(crate) dropped: u64,
+}
+
+impl Shard {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Shard {
+            inner: Mutex::new(ShardInner {
+                buf: AsyncBuf::with_capacity(capacity),
+                dropped: 0,
+            }),
+            wake_producers: Condvar::new(),
+        }
+    }
+}
+
+/// Returns the number of shards to use given the total buffer size and maximum entry size.
+///
+/// This is one per CPU, up to a limit, while keeping each shard large enough to hold an entry.
+pub(crate) fn shard_count(buf_size: usize, max_entry_size: usize) -> usize {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    cpus.min(MAX_SHARDS).min(buf_size / max_entry_size).max(1)
+}
+
+/// Returns the index of the current thread's shard, given the number of shards.
+///
+/// Threads are assigned to shards round-robin as they first log.
+pub(crate) fn shard_index(shards: usize) -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static ID: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    // During thread destruction, the thread-local may be unavailable; just use the first shard.
+    ID.try_with(|id| {
+        let id = id.get().unwrap_or_else(|| {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            id.set(Some(n));
+            n
+        });
+        id % shards
+    })
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncBuf;
+    use log::Level;
+
+    #[test]
+    fn evict_less_severe() {
+        let mut b = AsyncBuf::with_capacity(0);
+        b.push(b"i0\n", 1, Level::Info, 0);
+        b.push(b"d1\n", 1, Level::Debug, 1);
+        b.push(b"w2\n", 1, Level::Warn, 2);
+        b.push(b"d3\n", 1, Level::Debug, 3);
+        b.push(b"d4\n", 1, Level::Debug, 4);
+
+        // Only as many of the oldest debug entries as needed go.
+        assert_eq!(b.evict_less_severe(Level::Info, 12), 1);
+        assert_eq!(b.seqs, [0, 2, 3, 4]);
+        assert_eq!(b.evict_less_severe(Level::Info, 12), 0);
+
+        // Nothing as or more severe than the incoming level goes, even if that means there's
+        // no room.
+        assert_eq!(b.evict_less_severe(Level::Info, 0), 0);
+        assert_eq!(b.seqs, [0, 2, 3, 4]);
+        assert_eq!(b.evict_less_severe(Level::Info, 6), 2);
+        assert_eq!(b.entries().collect::<Vec<_>>(), [&b"i0\n"[..], b"w2\n"]);
+
+        // Less severe levels go first, spilling into the next level if needed.
+        assert_eq!(b.evict_less_severe(Level::Error, 3), 1);
+        assert_eq!(b.bytes, b"w2\n");
+        assert_eq!(b.evict_less_severe(Level::Error, 0), 1);
+        assert!(b.is_empty());
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = AsyncBuf::with_capacity(0);
+        a.push(b"0\n", 1, Level::Info, 0);
+        a.push(b"3\n", 1, Level::Info, 3);
+        let mut b = AsyncBuf::with_capacity(0);
+        b.push(b"1\n", 1, Level::Info, 1);
+        b.push(b"2\n", 2, Level::Warn, 2);
+        b.push(b"4\n", 1, Level::Info, 4);
+        let mut merged = AsyncBuf::with_capacity(0);
+        merged.merge_from(&[a, AsyncBuf::with_capacity(0), b]);
+        assert_eq!(merged.bytes, b"0\n1\n2\n3\n4\n");
+        assert_eq!(merged.seqs, [0, 1, 2, 3, 4]);
+        assert_eq!(merged.masks, [1, 1, 2, 1, 1]);
+        assert_eq!(merged.levels[2], Level::Warn);
+    }
+}
@@ -0,0 +1,143 @@
+//! Opt-in Unix domain socket for inspecting and changing the logger at runtime.
+//!
+//! The protocol is line-based: each line sent by the client is a command, and each gets a
+//! one-line response starting with `ok` or `error`. Commands:
+//!
+//! * `get-spec`: responds with the current logger-wide spec.
+//! * `set-spec SPEC`: replaces the logger-wide spec, as in `Handle::set_spec`. Unlike that
+//!   method, an invalid spec is rejected.
+//! * `flush`: waits for buffered entries to be written.
+//! * `stats`: responds with `key=value` counters.
+//!
+//! See `examples/mylogctl.rs` for a client.
+
+use crate::{Handle, Specification};
+use std::io::{self, BufRead as _, BufReader, Write as _};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// The longest a client may take to send a command or accept a response before being
+/// disconnected, so that a stuck client doesn't tie up its thread forever.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds to `path` and spawns a helper thread which accepts clients, each served on its own
+/// thread.
+///
+/// A stale socket file at `path` (one which refuses connections) is replaced.
+pub(crate) fn listen(handle: Handle, path: &Path) -> io::Result<()> {
+    let listener = match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() => {
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)?
+        }
+        r => r?,
+    };
+    std::thread::Builder::new()
+        .name("logger-control".to_owned())
+        .spawn(move || {
+            // Errors on one connection shouldn't affect the rest; discard them.
+            for conn in listener.incoming().flatten() {
+                let handle = handle.clone();
+                let _ = std::thread::Builder::new()
+                    .name("logger-control-conn".to_owned())
+                    .spawn(move || serve(&handle, conn));
+            }
+        })?;
+    Ok(())
+}
+
+fn serve(handle: &Handle, conn: UnixStream) -> io::Result<()> {
+    conn.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    conn.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut w = &conn;
+    for line in BufReader::new(&conn).lines() {
+        let line = line?;
+        let response = execute(handle, line.trim());
+        w.write_all(response.as_bytes())?;
+        w.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Executes a single command, returning the response line (without a trailing newline).
+fn execute(handle: &Handle, cmd: &str) -> String {
+    let (name, arg) = match cmd.find(' ') {
+        Some(i) => (&cmd[..i], cmd[i + 1..].trim()),
+        None => (cmd, ""),
+    };
+    match (name, arg) {
+        ("get-spec", "") => format!("ok {}", handle.0.spec.load()),
+        ("set-spec", spec) => match Specification::parse(spec) {
+            Ok(spec) => {
+                handle.set_specification(spec);
+                format!("ok {}", handle.0.spec.load())
+            }
+            Err(e) => format!("error {}", e),
+        },
+        ("flush", "") => {
+            log::Log::flush(&*handle.0);
+            "ok".to_owned()
+        }
+        ("stats", "") => format!("ok {}", handle.0.stats()),
+        ("get-spec", _) | ("flush", _) | ("stats", _) => {
+            format!("error {} takes no arguments", name)
+        }
+        _ => format!("error unknown command {:?}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::tempdir;
+    use crate::Builder;
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn commands() {
+        let dir = tempdir("control");
+        let path = dir.join("control");
+        let h = Builder::new().spec("info").is_test(true).build();
+        h.listen_control(&path).unwrap();
+
+        let conn = UnixStream::connect(&path).unwrap();
+        (&conn)
+            .write_all(
+                b"get-spec\nset-spec info,foo=debug\nset-spec foo=bogus\nflush\nstats\nbogus\n",
+            )
+            .unwrap();
+        conn.shutdown(std::net::Shutdown::Write).unwrap();
+        let lines: Vec<String> = BufReader::new(&conn).lines().map(Result::unwrap).collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "ok info");
+        assert_eq!(lines[1], "ok foo=debug,info");
+        assert_eq!(
+            lines[2],
+            "error logging directive \"foo=bogus\" at byte 0 has unparseable log level"
+        );
+        assert_eq!(lines[3], "ok");
+        assert!(lines[4].starts_with("ok entries=0 "), "{}", lines[4]);
+        assert_eq!(lines[5], "error unknown command \"bogus\"");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stuck_client() {
+        let dir = tempdir("control-stuck");
+        let path = dir.join("control");
+        let h = Builder::new().spec("info").is_test(true).build();
+        h.listen_control(&path).unwrap();
+
+        // A client which never sends a command doesn't hold up the next one.
+        let _stuck = UnixStream::connect(&path).unwrap();
+        let conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        (&conn).write_all(b"get-spec\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(&conn).read_line(&mut line).unwrap();
+        assert_eq!(line, "ok info\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
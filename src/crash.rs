@@ -0,0 +1,64 @@
+//! Opt-in hooks which write buffered entries when the process panics or exits; see
+//! `Handle::log_panics` and `Handle::drain_at_exit`.
+
+use crate::{Handle, Logger};
+use log::{Level, Record};
+use std::io;
+use std::sync::{Arc, OnceLock};
+
+/// The logger to drain at exit, if `drain_at_exit` has been called.
+static AT_EXIT: OnceLock<Arc<Logger>> = OnceLock::new();
+
+extern "C" fn on_exit() {
+    if let Some(logger) = AT_EXIT.get() {
+        logger.drain(None);
+    }
+}
+
+pub(crate) fn drain_at_exit(handle: Handle) -> io::Result<()> {
+    if AT_EXIT.set(handle.0).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "atexit handler already registered",
+        ));
+    }
+
+    // SAFETY: `on_exit` is a valid function for the life of the program.
+    if unsafe { libc::atexit(on_exit) } != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "unable to register atexit handler",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn log_panics(handle: Handle) {
+    std::panic::set_hook(Box::new(move |info| {
+        let msg = match info.payload().downcast_ref::<&str>() {
+            Some(s) => *s,
+            None => match info.payload().downcast_ref::<String>() {
+                Some(s) => &s[..],
+                None => "Box<dyn Any>",
+            },
+        };
+        let thread = std::thread::current();
+        let location = info.location();
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        handle.0.drain(Some(
+            &Record::builder()
+                .args(format_args!(
+                    "thread '{}' panicked at {}: {}\nstack backtrace:\n{}",
+                    thread.name().unwrap_or("<unnamed>"),
+                    location.map_or_else(|| "<unknown>".to_owned(), |l| l.to_string()),
+                    msg,
+                    backtrace,
+                ))
+                .level(Level::Error)
+                .target("panic")
+                .file(location.map(|l| l.file()))
+                .line(location.map(|l| l.line()))
+                .build(),
+        ));
+    }));
+}
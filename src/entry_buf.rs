@@ -1,6 +1,6 @@
 //! Module limiting the unsafe scope of the `EntryBuf` type.
 
-use super::MAX_ENTRY_SIZE;
+use std::mem::MaybeUninit;
 
 /// Represents an `EntryBuf`'s initial state, in which the caller is writing the entry.
 pub(crate) enum Writing {}
@@ -14,32 +14,76 @@ pub(crate) trait State {}
 impl State for Writing {}
 impl State for Reading {}
 
-/// A buffer for a single entry, intended to be placed on the stack.
-pub(crate) struct EntryBuf<S: State> {
-    /// The actual buffer.
-    /// Safety invariant: `&buf[0..len]` is initialized and valid UTF-8.
-    buf: std::mem::MaybeUninit<[u8; MAX_ENTRY_SIZE]>,
+/// The stack size classes used by `with_entry_buf`, smallest first.
+const STACK_SIZES: [usize; 4] = [1 << 10, 1 << 12, 1 << 14, 1 << 16];
 
-    /// The number of bytes of `buf` which are initialized, in range `[0, MAX_ENTRY_SIZE]`.
-    /// In state `Writing`, the range is further reduced to `[0, MAX_ENTRY_SIZE)`, as the final
+/// A buffer for a single entry, backed by caller-supplied storage (typically on the stack, via
+/// `with_entry_buf`).
+pub(crate) struct EntryBuf<'a, S: State> {
+    /// The actual buffer, of length at least one (to fit the trailing `\n`).
+    /// Safety invariant: `&buf[0..len]` is initialized.
+    ///
+    /// It is also valid UTF-8 unless the binary `write_bytes` or `overwrite` methods were used:
+    /// `write_str` never splits a UTF-8 sequence.
+    buf: &'a mut [MaybeUninit<u8>],
+
+    /// The number of bytes of `buf` which are initialized, in range `[0, buf.len()]`.
+    /// In state `Writing`, the range is further reduced to `[0, limit]`, as the final
     /// byte is reserved for the newline.
     len: usize,
 
+    /// The maximum `len` that `write_str` will fill to, in range `[0, buf.len())`.
+    /// This is `buf.len() - 1` less any bytes held back via `set_reserved`.
+    limit: usize,
+
     _state: std::marker::PhantomData<S>,
 }
 
-impl EntryBuf<Writing> {
-    pub(crate) fn new() -> Self {
+/// Calls `f` with an empty `EntryBuf` which can hold `capacity` bytes (including the trailing
+/// `\n`).
+///
+/// The storage is on the stack, using the smallest size class which fits, so that small
+/// entry sizes need little stack. Capacities beyond the largest size class use the heap.
+pub(crate) fn with_entry_buf<R>(
+    capacity: usize,
+    f: impl for<'a> FnOnce(EntryBuf<'a, Writing>) -> R,
+) -> R {
+    /// Uses stack storage of `N` bytes. This is a separate function so that callers' stack
+    /// frames are only as large as the size class in use.
+    #[inline(never)]
+    fn on_stack<const N: usize, R>(
+        capacity: usize,
+        f: impl for<'a> FnOnce(EntryBuf<'a, Writing>) -> R,
+    ) -> R {
+        let mut storage = [MaybeUninit::uninit(); N];
+        f(EntryBuf::new(&mut storage[..capacity]))
+    }
+
+    match capacity {
+        c if c <= STACK_SIZES[0] => on_stack::<{ STACK_SIZES[0] }, R>(c, f),
+        c if c <= STACK_SIZES[1] => on_stack::<{ STACK_SIZES[1] }, R>(c, f),
+        c if c <= STACK_SIZES[2] => on_stack::<{ STACK_SIZES[2] }, R>(c, f),
+        c if c <= STACK_SIZES[3] => on_stack::<{ STACK_SIZES[3] }, R>(c, f),
+        c => f(EntryBuf::new(&mut vec![MaybeUninit::uninit(); c])),
+    }
+}
+
+impl<'a> EntryBuf<'a, Writing> {
+    /// Creates an empty buffer backed by `buf`, which must have length at least one.
+    pub(crate) fn new(buf: &'a mut [MaybeUninit<u8>]) -> Self {
+        assert!(!buf.is_empty());
+        let limit = buf.len() - 1;
         Self {
-            buf: std::mem::MaybeUninit::uninit(),
+            buf,
             len: 0,
+            limit,
             _state: std::marker::PhantomData,
         }
     }
 
     /// Terminates with a newline, using the reserved last byte if necessary.
-    pub(crate) fn terminate(mut self) -> EntryBuf<Reading> {
-        debug_assert!(self.len < MAX_ENTRY_SIZE);
+    pub(crate) fn terminate(mut self) -> EntryBuf<'a, Reading> {
+        debug_assert!(self.len < self.buf.len());
         unsafe {
             *self.unwritten() = b'\n';
         }
@@ -47,10 +91,73 @@ impl EntryBuf<Writing> {
         EntryBuf {
             buf: self.buf,
             len: self.len,
+            limit: self.len,
             _state: std::marker::PhantomData,
         }
     }
 
+    /// Holds back `reserved` bytes (in addition to the newline) from subsequent writes.
+    ///
+    /// This allows formats with closing syntax (such as JSON's `"}`) to guarantee room for it
+    /// even when the body is truncated. Call again with `0` before writing the closing syntax.
+    pub(crate) fn set_reserved(&mut self, reserved: usize) {
+        self.limit = (self.buf.len() - 1).saturating_sub(reserved);
+    }
+
+    /// Returns the number of bytes written so far, for use with `rollback`.
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Discards everything written after `len`, which must have been returned by `len()`.
+    pub(crate) fn rollback(&mut self, len: usize) {
+        assert!(len <= self.len);
+        self.len = len;
+    }
+
+    /// Writes all of `s` or (if it doesn't fit) nothing, returning `Err` in the latter case.
+    ///
+    /// This is useful for escape sequences and other syntax which would be corrupted by being
+    /// cut in the middle.
+    pub(crate) fn write_whole(&mut self, s: &str) -> Result<(), std::fmt::Error> {
+        if s.len() > self.limit.saturating_sub(self.len) {
+            return Err(std::fmt::Error);
+        }
+        std::fmt::Write::write_str(self, s)
+    }
+
+    /// Writes all of `b` or (if it doesn't fit) nothing, as in `write_whole`.
+    ///
+    /// This is intended for binary formats; text formats should use `write_str` or `write_whole`
+    /// to keep the entry valid UTF-8.
+    pub(crate) fn write_bytes(&mut self, b: &[u8]) -> Result<(), std::fmt::Error> {
+        if b.len() > self.limit.saturating_sub(self.len) {
+            return Err(std::fmt::Error);
+        }
+        // SAFETY: there's room for `b` as checked above.
+        unsafe {
+            std::ptr::copy_nonoverlapping(b.as_ptr(), self.unwritten(), b.len());
+        }
+        self.len += b.len();
+        Ok(())
+    }
+
+    /// Overwrites already-written bytes starting at `pos`, such as a length placeholder.
+    ///
+    /// Panics if the range is not entirely written.
+    pub(crate) fn overwrite(&mut self, pos: usize, b: &[u8]) {
+        assert!(pos <= self.len && b.len() <= self.len - pos);
+        // SAFETY: the range is in-bounds as checked above.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                b.as_ptr(),
+                (self.buf.as_mut_ptr() as *mut u8).add(pos),
+                b.len(),
+            );
+        }
+    }
+
     /// Gets a pointer to the unwritten/uninitialized portion of the buffer.
     /// This is returned as a raw pointer because it's unsound to take a reference to it.
     fn unwritten(&mut self) -> *mut u8 {
@@ -58,37 +165,32 @@ impl EntryBuf<Writing> {
     }
 }
 
-impl EntryBuf<Reading> {
+impl<'a> EntryBuf<'a, Reading> {
     /// Gets the written/initialized prefix of the buffer.
-    pub(crate) fn get(&self) -> &str {
+    ///
+    /// This is valid UTF-8 for all text formats.
+    pub(crate) fn get(&self) -> &[u8] {
         // SAFETY:
-        // * `self.len <= MAX_ENTRY_SIZE` so the slice is in-bounds.
-        // * `&self.buf[0..self.len]` is initialized and valid UTF-8 by
-        //   construction and the `write_str` method.
-        unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                self.buf.as_ptr() as *const u8,
-                self.len,
-            ))
-        }
+        // * `self.len <= self.buf.len()` so the slice is in-bounds.
+        // * `&self.buf[0..self.len]` is initialized by construction.
+        unsafe { std::slice::from_raw_parts(self.buf.as_ptr() as *const u8, self.len) }
     }
 }
 
-impl std::fmt::Write for EntryBuf<Writing> {
+impl<'a> std::fmt::Write for EntryBuf<'a, Writing> {
     /// Writes as many full UTF-8 sequences as possible from of `s` into the
     /// buffer without using the reserved last byte, returning `Err` on
     /// truncation. Note this behavior is different than say
     /// `arrayvec::{ArrayVec, ArrayString}`, which write nothing if the entire
     /// entry doesn't fit.
     fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
-        if self.len == MAX_ENTRY_SIZE {
-            // This path can only be taken if terminate() was already called.
+        if self.len >= self.limit {
             return Err(std::fmt::Error);
         }
         let s = s.as_bytes();
         let mut to_write = s.len();
-        if to_write > MAX_ENTRY_SIZE - 1 - self.len {
-            to_write = MAX_ENTRY_SIZE - 1 - self.len;
+        if to_write > self.limit - self.len {
+            to_write = self.limit - self.len;
             while to_write > 0 && (s[to_write] & 0b1100_0000) == 0b1000_0000 {
                 // We cut in the middle of a UTF-8 sequence; back up to the start of the sequence.
                 to_write -= 1;
@@ -108,59 +210,147 @@ impl std::fmt::Write for EntryBuf<Writing> {
 
 #[cfg(test)]
 mod tests {
-    use super::{EntryBuf, MAX_ENTRY_SIZE};
+    use super::{with_entry_buf, EntryBuf};
+    use crate::MAX_ENTRY_SIZE;
     use std::fmt::Write;
+    use std::mem::MaybeUninit;
 
     /// Tests that an entry well under the limit is not truncated.
     #[test]
     fn well_under_limit() {
-        let mut buf = EntryBuf::new();
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
         buf.write_str("foo ").unwrap();
         buf.write_str("bar").unwrap();
         let buf = buf.terminate();
-        assert_eq!(buf.get(), "foo bar\n");
+        assert_eq!(buf.get(), "foo bar\n".as_bytes());
     }
 
     /// Tests that an entry one under the limit is not truncated (it just fits with the `\n`).
     #[test]
     fn one_under_limit() {
-        let mut buf = EntryBuf::new();
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
         let e = "e".repeat(MAX_ENTRY_SIZE - 1);
         buf.write_str(&e).unwrap();
         let buf = buf.terminate();
-        assert_eq!(buf.get(), format!("{e}\n"));
+        assert_eq!(buf.get(), format!("{e}\n").as_bytes());
     }
 
     /// Tests that an entry at the limit is truncated and still ends in '\n'.
     #[test]
     fn at_limit() {
-        let mut buf = EntryBuf::new();
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
         let e = "e".repeat(MAX_ENTRY_SIZE);
         buf.write_str(&e).unwrap_err();
         let e_shortened = &e[0..MAX_ENTRY_SIZE - 1];
         let buf = buf.terminate();
-        assert_eq!(buf.get(), format!("{e_shortened}\n"));
+        assert_eq!(buf.get(), format!("{e_shortened}\n").as_bytes());
     }
 
     /// Tests that a multi-byte UTF-8 character is not split.
     #[test]
     fn multi_byte_utf8() {
-        let mut buf = EntryBuf::new();
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
         let e = "e".repeat(MAX_ENTRY_SIZE - 2) + "é";
         buf.write_str(&e).unwrap_err();
         let e_shortened = &e[0..MAX_ENTRY_SIZE - 2];
         let buf = buf.terminate();
-        assert_eq!(buf.get(), format!("{e_shortened}\n"));
+        assert_eq!(buf.get(), format!("{e_shortened}\n").as_bytes());
+    }
+
+    /// Tests that reserved bytes are held back until released.
+    #[test]
+    fn reserved() {
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
+        buf.set_reserved(2);
+        let e = "e".repeat(MAX_ENTRY_SIZE);
+        buf.write_str(&e).unwrap_err();
+        buf.write_str("x").unwrap_err();
+        buf.set_reserved(0);
+        buf.write_str("}}").unwrap();
+        let buf = buf.terminate();
+        assert_eq!(
+            buf.get(),
+            format!("{}}}}}\n", &e[0..MAX_ENTRY_SIZE - 3]).as_bytes()
+        );
+    }
+
+    /// Tests that `write_whole` writes nothing if the string doesn't fit.
+    #[test]
+    fn write_whole() {
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
+        let e = "e".repeat(MAX_ENTRY_SIZE - 3);
+        buf.write_str(&e).unwrap();
+        buf.write_whole("\\u0001").unwrap_err();
+        buf.write_whole("ab").unwrap();
+        let buf = buf.terminate();
+        assert_eq!(buf.get(), format!("{e}ab\n").as_bytes());
+    }
+
+    /// Tests binary writes and overwrites.
+    #[test]
+    fn bytes() {
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
+        buf.write_str("k\n").unwrap();
+        let pos = buf.len();
+        buf.write_bytes(&[0; 2]).unwrap();
+        buf.write_str("v").unwrap();
+        buf.overwrite(pos, &[1, 0]);
+        let e = vec![0u8; MAX_ENTRY_SIZE];
+        buf.write_bytes(&e).unwrap_err();
+        let buf = buf.terminate();
+        assert_eq!(buf.get(), b"k\n\x01\x00v\n");
+    }
+
+    /// Tests that `rollback` discards a partial write.
+    #[test]
+    fn rollback() {
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
+        buf.write_str("foo").unwrap();
+        let mark = buf.len();
+        buf.write_str(" bar").unwrap();
+        buf.rollback(mark);
+        let buf = buf.terminate();
+        assert_eq!(buf.get(), "foo\n".as_bytes());
     }
 
     /// Tests that an entry over the limit is truncated and still ends in '\n'.
     #[test]
     fn over_limit() {
-        let mut buf = EntryBuf::new();
+        let mut storage = [MaybeUninit::uninit(); MAX_ENTRY_SIZE];
+        let mut buf = EntryBuf::new(&mut storage);
         let e = "e".repeat(MAX_ENTRY_SIZE + 1);
         buf.write_str(&e).unwrap_err();
         let e_shortened = &e[0..MAX_ENTRY_SIZE - 1];
         let buf = buf.terminate();
-        assert_eq!(buf.get(), format!("{e_shortened}\n"));
+        assert_eq!(buf.get(), format!("{e_shortened}\n").as_bytes());
+    }
+
+    /// Tests each size class, and the heap.
+    #[test]
+    fn size_classes() {
+        for capacity in [
+            1,
+            2,
+            1 << 10,
+            (1 << 10) + 1,
+            1 << 16,
+            (1 << 16) + 1,
+            1 << 20,
+        ] {
+            let e = "e".repeat(capacity);
+            let got = with_entry_buf(capacity, |mut buf| {
+                buf.write_str(&e).unwrap_err();
+                buf.terminate().get().to_owned()
+            });
+            assert_eq!(got, format!("{}\n", &e[..capacity - 1]).as_bytes());
+        }
     }
 }
@@ -0,0 +1,180 @@
+//! glog-style fatal entries; see `fatal!`.
+
+use crate::GLOBAL;
+use log::{Level, Record};
+use std::cell::Cell;
+
+thread_local! {
+    static FATAL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns true iff the entry being formatted on this thread is from `fatal!`.
+///
+/// Such entries have level `Error`; formats with a more severe level use it instead.
+pub(crate) fn is_fatal() -> bool {
+    FATAL.try_with(Cell::get).unwrap_or(false)
+}
+
+/// Logs an entry at `error` level, marked as fatal, then aborts the process, as glog's
+/// `LOG(FATAL)` does.
+///
+/// The entry is written synchronously, after all buffered entries, and includes a backtrace.
+/// As with `Handle::log_panics`, this waits only briefly for the logger thread to finish any
+/// batch it's in the middle of writing.
+/// If this crate's logger is installed, the entry bypasses the logger-wide spec (but not sink
+/// specs), and formats which have a more severe level than `error` use it: `F` in
+/// `Format::Google`, `<2>` (`SD_CRIT`) in `Format::GoogleSystemd`, `fatal` in `Format::Json`
+/// and `Format::Logfmt`, and priority 2 (`LOG_CRIT`) for journald and syslog. Otherwise, the
+/// entry is passed to the installed `log` implementation and flushed.
+///
+/// ```no_run
+/// # let path = "/etc/foo.conf";
+/// mylog::fatal!("unable to read {}", path);
+/// ```
+#[macro_export]
+macro_rules! fatal {
+    (target: $target:expr, $($arg:tt)+) => {
+        $crate::__fatal(
+            $target,
+            module_path!(),
+            file!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+    ($($arg:tt)+) => {
+        $crate::fatal!(target: module_path!(), $($arg)+)
+    };
+}
+
+#[doc(hidden)]
+pub fn __fatal(
+    target: &str,
+    module_path: &'static str,
+    file: &'static str,
+    line: u32,
+    args: std::fmt::Arguments,
+) -> ! {
+    FATAL.with(|f| f.set(true));
+    let backtrace = std::backtrace::Backtrace::force_capture();
+    write(
+        &Record::builder()
+            .args(format_args!("{}\nstack backtrace:\n{}", args, backtrace))
+            .level(Level::Error)
+            .target(target)
+            .module_path_static(Some(module_path))
+            .file_static(Some(file))
+            .line(Some(line))
+            .build(),
+    );
+    std::process::abort()
+}
+
+/// Writes `record` synchronously, after any buffered entries.
+fn write(record: &Record) {
+    match GLOBAL.get() {
+        Some(logger) => logger.drain(Some(record)),
+        None => {
+            let logger = log::logger();
+            logger.log(record);
+            logger.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FATAL;
+    use crate::{testutil, Builder, Destination, Format, FormatContext};
+    use log::{Level, Record};
+
+    fn format(fmt: Format) -> String {
+        let record = Record::builder()
+            .args(format_args!("boom"))
+            .level(Level::Error)
+            .target("foo")
+            .build();
+        testutil::format(crate::MAX_ENTRY_SIZE, |buf| {
+            fmt.write(&FormatContext::default(), &record, buf)
+        })
+    }
+
+    #[test]
+    fn levels() {
+        FATAL.with(|f| f.set(true));
+        assert!(format(Format::Google).starts_with('F'));
+        assert!(format(Format::GoogleSystemd).starts_with("<2>"));
+        assert!(format(Format::Json).contains(r#""level":"fatal""#));
+        assert!(format(Format::Logfmt).contains(" level=fatal "));
+        FATAL.with(|f| f.set(false));
+        assert!(format(Format::Google).starts_with('E'));
+    }
+
+    /// The number of entries `fatal_child` logs before `fatal!`.
+    const BUFFERED: usize = 4;
+
+    /// Runs `fatal_child` in a subprocess, checking that it writes the entry and aborts.
+    #[test]
+    fn aborts() {
+        let out = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "fatal::tests::fatal_child", "--nocapture"])
+            .env("MYLOG_FATAL_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let lines: Vec<&str> = stderr.lines().collect();
+        let fatal = lines
+            .iter()
+            .position(|l| l.contains("] boom 42"))
+            .unwrap_or_else(|| panic!("no fatal entry in:\n{}", stderr));
+        assert!(lines[fatal].starts_with('F'), "{}", lines[fatal]);
+
+        // Every entry logged before `fatal!`, whether still buffered or already taken by the
+        // logger thread, is written before the fatal entry, in order.
+        let buffered: Vec<&str> = lines[..fatal]
+            .iter()
+            .filter_map(|l| l.split_once("] buffered ").map(|(_, i)| i))
+            .collect();
+        let expected: Vec<String> = (0..BUFFERED).map(|i| i.to_string()).collect();
+        assert_eq!(buffered, expected, "{}", stderr);
+        assert!(stderr.contains("stack backtrace:"), "{}", stderr);
+    }
+
+    #[test]
+    fn fatal_child() {
+        if std::env::var_os("MYLOG_FATAL_CHILD").is_none() {
+            return;
+        }
+        let h = Builder::new()
+            .spec("warn")
+            .destination(Destination::Stderr)
+            .async_mode(true)
+            .build();
+        h.clone().install().unwrap();
+
+        // Stall the logger thread for a while after it takes the first entry, so that it's still
+        // in flight when `fatal!` drains the rest.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let logger = h.0.clone();
+        std::thread::spawn(move || {
+            let _stall = logger.stall.lock().unwrap();
+            tx.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+        });
+        rx.recv().unwrap();
+        log::warn!("buffered 0");
+        while h
+            .0
+            .shards
+            .iter()
+            .any(|s| !s.inner.lock().unwrap().buf.is_empty())
+        {
+            std::thread::yield_now();
+        }
+        for i in 1..BUFFERED {
+            log::warn!("buffered {}", i);
+        }
+        crate::fatal!("boom {}", 42);
+    }
+}
@@ -0,0 +1,472 @@
+//! Destination appending to a file, with optional size- and time-based rotation.
+
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Configuration for `Destination::File`.
+///
+/// ```no_run
+/// let dest = mylog::Destination::File(
+///     mylog::LogFile::new("/var/log/moonfire-nvr.log")
+///         .max_size(100 << 20)
+///         .rotate_every(mylog::RotationInterval::Daily)
+///         .keep(7),
+/// );
+/// ```
+///
+/// When rotating, `foo.log` is renamed to `foo.log.1`, `foo.log.1` to `foo.log.2`, and so on,
+/// discarding the oldest file beyond the configured number to keep. With compression (see
+/// `compress`, which requires the `gzip` feature), rotated files are named `foo.log.1.gz` etc.
+///
+/// Rotation only happens between entries; an entry is never split across files. It happens on
+/// the asynchronous logger thread while `Handle::async_scope` is active, or otherwise within the
+/// logging call which triggers it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    interval: Option<RotationInterval>,
+    time_zone: Option<jiff::tz::TimeZone>,
+    keep: usize,
+    compress: bool,
+}
+
+/// A wall-clock interval on which to rotate log files.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RotationInterval {
+    /// Rotate at the start of each hour.
+    Hourly,
+
+    /// Rotate at midnight.
+    Daily,
+}
+
+impl LogFile {
+    /// Appends to the given path, creating it if necessary. By default, never rotates.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LogFile {
+            path: path.into(),
+            max_size: None,
+            interval: None,
+            time_zone: None,
+            keep: 5,
+            compress: false,
+        }
+    }
+
+    /// Rotates before an entry would take the file beyond `max_size` bytes.
+    ///
+    /// A single entry larger than this is still written, to an otherwise-empty file.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Rotates at the start of each interval.
+    pub fn rotate_every(mut self, interval: RotationInterval) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Sets the time zone in which `rotate_every` intervals start; default is the system's.
+    pub fn time_zone(mut self, time_zone: jiff::tz::TimeZone) -> Self {
+        self.time_zone = Some(time_zone);
+        self
+    }
+
+    /// Sets how many rotated files to keep; default is 5.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gzip-compresses rotated files; default is false.
+    #[cfg(feature = "gzip")]
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+}
+
+/// An opened `LogFile` destination.
+pub(crate) struct Writer {
+    config: LogFile,
+    time_zone: jiff::tz::TimeZone,
+    state: Mutex<State>,
+
+    /// Set by tests to make compression fail.
+    #[cfg(test)]
+    fail_compress: std::sync::atomic::AtomicBool,
+}
+
+struct State {
+    file: std::fs::File,
+
+    /// The current size of `file`, to compare against `LogFile::max_size`.
+    size: u64,
+
+    /// When to next rotate due to `LogFile::rotate_every`.
+    next_rotation: Option<jiff::Timestamp>,
+}
+
+impl Writer {
+    pub(crate) fn open(config: LogFile) -> io::Result<Self> {
+        let time_zone = config
+            .time_zone
+            .clone()
+            .unwrap_or_else(jiff::tz::TimeZone::system);
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        let next_rotation = next_rotation(config.interval, &time_zone);
+        Ok(Writer {
+            config,
+            time_zone,
+            state: Mutex::new(State {
+                file,
+                size,
+                next_rotation,
+            }),
+            #[cfg(test)]
+            fail_compress: std::sync::atomic::AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the entries in `bytes` (ending at the given offsets), rotating between them as
+    /// necessary.
+    ///
+    /// Consecutive entries which belong in the same file are written with a single call.
+    pub(crate) fn write_entries(&self, bytes: &[u8], ends: &[usize]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let now = state.next_rotation.map(|_| jiff::Timestamp::now());
+        let mut pending_start = 0;
+        let mut result = Ok(());
+        let mut start = 0;
+        for &end in ends {
+            let pending_len = (start - pending_start) as u64;
+            if self.should_rotate(&state, pending_len, (end - start) as u64, now) {
+                if let Err(e) = state.write(&bytes[pending_start..start]) {
+                    result = Err(e);
+                }
+                pending_start = start;
+                self.rotate(&mut state);
+            }
+            start = end;
+        }
+        if let Err(e) = state.write(&bytes[pending_start..start]) {
+            result = Err(e);
+        }
+        result
+    }
+
+    /// Closes and reopens the file, e.g. after an external tool such as `logrotate` has renamed
+    /// it. On failure, reports the error on stderr and continues with the current file.
+    pub(crate) fn reopen(&self) {
+        let mut state = self.state.lock().unwrap();
+        let result =
+            open_append(&self.config.path).and_then(|f| f.metadata().map(|m| (f, m.len())));
+        match result {
+            Ok((file, size)) => {
+                state.file = file;
+                state.size = size;
+            }
+            Err(e) => {
+                let _ = writeln!(
+                    io::stderr(),
+                    "unable to reopen log file {}: {}",
+                    self.config.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Returns true if the file should be rotated before writing an entry of length `entry_len`,
+    /// given that `pending_len` bytes are already destined for the current file.
+    fn should_rotate(
+        &self,
+        state: &State,
+        pending_len: u64,
+        entry_len: u64,
+        now: Option<jiff::Timestamp>,
+    ) -> bool {
+        if let (Some(next), Some(now)) = (state.next_rotation, now) {
+            if now >= next {
+                return true;
+            }
+        }
+        let size = state.size + pending_len;
+        match self.config.max_size {
+            Some(max) => size > 0 && size + entry_len > max,
+            None => false,
+        }
+    }
+
+    /// Rotates, reporting any error on stderr and continuing with the current file if needed.
+    fn rotate(&self, state: &mut State) {
+        state.next_rotation = next_rotation(self.config.interval, &self.time_zone);
+
+        // Don't retry until another `max_size` bytes have been written.
+        state.size = 0;
+        let result = self.shift().and_then(|()| {
+            // `path` has been moved away, so switch to a new file even if compression fails.
+            state.file = open_append(&self.config.path)?;
+            self.compress_first()
+        });
+        if let Err(e) = result {
+            let _ = writeln!(
+                io::stderr(),
+                "unable to rotate log file {}: {}",
+                self.config.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Returns the path of the `i`th rotated file, with the given suffix.
+    fn rotated(&self, i: usize, suffix: &str) -> PathBuf {
+        let mut p = self.config.path.clone().into_os_string();
+        p.push(format!(".{}{}", i, suffix));
+        PathBuf::from(p)
+    }
+
+    /// Shifts `path` to `path.1`, `path.1` to `path.2`, etc.
+    fn shift(&self) -> io::Result<()> {
+        let path = &self.config.path;
+        let suffix = if self.config.compress { ".gz" } else { "" };
+        if self.config.keep == 0 {
+            return remove_if_exists(path);
+        }
+
+        // If compression failed last time, `path.1` is still uncompressed and would be
+        // overwritten below. Retry, giving up on this rotation if it fails again.
+        if self.config.compress && self.rotated(1, "").exists() {
+            self.compress_first()?;
+        }
+        remove_if_exists(&self.rotated(self.config.keep, suffix))?;
+        for i in (1..self.config.keep).rev() {
+            rename_if_exists(&self.rotated(i, suffix), &self.rotated(i + 1, suffix))?;
+        }
+        std::fs::rename(path, self.rotated(1, ""))
+    }
+
+    /// Compresses `path.1` to `path.1.gz` after `shift`, if configured.
+    fn compress_first(&self) -> io::Result<()> {
+        if !self.config.compress || self.config.keep == 0 {
+            return Ok(());
+        }
+        let first = self.rotated(1, "");
+        #[cfg(test)]
+        if self
+            .fail_compress
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected failure"));
+        }
+        compress(&first, &self.rotated(1, ".gz"))?;
+        std::fs::remove_file(&first)
+    }
+}
+
+impl State {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.size += buf.len() as u64;
+        self.file.write_all(buf)
+    }
+}
+
+fn next_rotation(
+    interval: Option<RotationInterval>,
+    time_zone: &jiff::tz::TimeZone,
+) -> Option<jiff::Timestamp> {
+    use jiff::ToSpan as _;
+    let now = jiff::Timestamp::now().to_zoned(time_zone.clone());
+    let next = match interval? {
+        RotationInterval::Hourly => now
+            .round(
+                jiff::ZonedRound::new()
+                    .smallest(jiff::Unit::Hour)
+                    .mode(jiff::RoundMode::Trunc),
+            )
+            .and_then(|z| z.checked_add(1.hour())),
+        RotationInterval::Daily => now.tomorrow().and_then(|z| z.start_of_day()),
+    };
+    Some(next.expect("next rotation time is in range").timestamp())
+}
+
+fn open_append(path: &Path) -> io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = std::fs::File::open(from)?;
+    let output = std::fs::File::create(to)?;
+    let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+#[cfg(not(feature = "gzip"))]
+fn compress(_from: &Path, _to: &Path) -> io::Result<()> {
+    unreachable!("compress is only settable with the gzip feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogFile, Writer};
+    use crate::testutil::tempdir;
+    use std::path::PathBuf;
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = tempdir("file-size");
+        let path = dir.join("log");
+        let w = Writer::open(LogFile::new(&path).max_size(10).keep(2)).unwrap();
+        w.write_entries(b"aaaa\nbbbb\ncccc\ndddd\neeee\n", &[5, 10, 15, 20, 25])
+            .unwrap();
+        assert_eq!(read(path.clone()), "eeee\n");
+        assert_eq!(read(dir.join("log.1")), "cccc\ndddd\n");
+        assert_eq!(read(dir.join("log.2")), "aaaa\nbbbb\n");
+        assert!(!dir.join("log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_entry() {
+        let dir = tempdir("file-oversized");
+        let path = dir.join("log");
+        let w = Writer::open(LogFile::new(&path).max_size(4)).unwrap();
+        w.write_entries(b"too long\n", &[9]).unwrap();
+        w.write_entries(b"x\n", &[2]).unwrap();
+        assert_eq!(read(path), "x\n");
+        assert_eq!(read(dir.join("log.1")), "too long\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appends_to_existing() {
+        let dir = tempdir("file-existing");
+        let path = dir.join("log");
+        std::fs::write(&path, "old\n").unwrap();
+        let w = Writer::open(LogFile::new(&path).max_size(6)).unwrap();
+        w.write_entries(b"new\n", &[4]).unwrap();
+        assert_eq!(read(path), "new\n");
+        assert_eq!(read(dir.join("log.1")), "old\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn next_rotation() {
+        let tz = jiff::tz::TimeZone::fixed(jiff::tz::offset(-8));
+        let now = jiff::Timestamp::now();
+        for interval in [
+            super::RotationInterval::Hourly,
+            super::RotationInterval::Daily,
+        ] {
+            let next = super::next_rotation(Some(interval), &tz).unwrap();
+            assert!(next > now);
+            let z = next.to_zoned(tz.clone());
+            assert_eq!((z.minute(), z.second()), (0, 0));
+            if interval == super::RotationInterval::Daily {
+                assert_eq!(z.hour(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = tempdir("file-reopen");
+        let path = dir.join("log");
+        let w = Writer::open(LogFile::new(&path)).unwrap();
+        w.write_entries(b"a\n", &[2]).unwrap();
+        std::fs::rename(&path, dir.join("log.old")).unwrap();
+        w.write_entries(b"b\n", &[2]).unwrap();
+        w.reopen();
+        w.write_entries(b"c\n", &[2]).unwrap();
+        assert_eq!(read(dir.join("log.old")), "a\nb\n");
+        assert_eq!(read(path), "c\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compress() {
+        use std::io::Read as _;
+        let dir = tempdir("file-compress");
+        let path = dir.join("log");
+        let w = Writer::open(LogFile::new(&path).max_size(4).compress(true)).unwrap();
+        w.write_entries(b"aaa\nbbb\n", &[4, 8]).unwrap();
+        assert_eq!(read(path), "bbb\n");
+        assert!(!dir.join("log.1").exists());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(dir.join("log.1.gz")).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "aaa\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a rotated file left uncompressed by a failure isn't overwritten later.
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compress_failure() {
+        use std::io::Read as _;
+        use std::sync::atomic::Ordering;
+        let dir = tempdir("file-compress-failure");
+        let path = dir.join("log");
+        let w = Writer::open(LogFile::new(&path).max_size(4).compress(true)).unwrap();
+        w.fail_compress.store(true, Ordering::Relaxed);
+        w.write_entries(b"aaa\nbbb\n", &[4, 8]).unwrap();
+        assert_eq!(read(path.clone()), "bbb\n");
+        assert_eq!(read(dir.join("log.1")), "aaa\n");
+
+        // Another failure leaves everything in place, still writing to the current file.
+        w.write_entries(b"ccc\n", &[4]).unwrap();
+        assert_eq!(read(path.clone()), "bbb\nccc\n");
+        assert_eq!(read(dir.join("log.1")), "aaa\n");
+
+        w.fail_compress.store(false, Ordering::Relaxed);
+        w.write_entries(b"ddd\n", &[4]).unwrap();
+        let gunzip = |name: &str| {
+            let mut decoded = String::new();
+            flate2::read::GzDecoder::new(std::fs::File::open(dir.join(name)).unwrap())
+                .read_to_string(&mut decoded)
+                .unwrap();
+            decoded
+        };
+        assert_eq!(read(path), "ddd\n");
+        assert!(!dir.join("log.1").exists());
+        assert_eq!(gunzip("log.1.gz"), "bbb\nccc\n");
+        assert_eq!(gunzip("log.2.gz"), "aaa\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
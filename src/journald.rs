@@ -0,0 +1,393 @@
+//! Destination speaking the native
+//! [systemd-journald protocol](https://systemd.io/JOURNAL_NATIVE_PROTOCOL/).
+//!
+//! Unlike `Format::GoogleSystemd` on stderr, this sends each entry's metadata as separate
+//! journal fields, so e.g. `journalctl TARGET=moonfire_nvr::db` works.
+
+use crate::entry_buf::{EntryBuf, Writing};
+use log::{Level, Record};
+use std::fmt::{Display, Write as _};
+use std::io::{self, Write as _};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// The well-known path of journald's native protocol socket.
+pub(crate) const SOCKET_PATH: &str = "/run/systemd/journal/socket";
+
+/// The header of the final `MESSAGE` field: name, newline, and 64-bit length placeholder.
+///
+/// Space for this is reserved throughout the entry, so it always fits.
+const MESSAGE_HEADER_LEN: usize = "MESSAGE\n".len() + 8;
+
+/// Writes `record` as a native journal entry.
+///
+/// The entry consists of `PRIORITY`, `CODE_FILE`, `CODE_LINE`, `CODE_MODULE`, `TARGET`,
+/// `THREAD`, a field for each key-value attached to the record, and finally `MESSAGE`. The
+/// trailing newline of the last field is supplied by `EntryBuf::terminate`.
+///
+/// Fields which don't fit are omitted; the message is truncated if necessary.
+pub(crate) fn write(record: &Record, buf: &mut EntryBuf<Writing>) -> Result<(), std::fmt::Error> {
+    buf.set_reserved(MESSAGE_HEADER_LEN);
+    let _ = buf.write_str(priority(record.level()));
+    if let Some(file) = record.file() {
+        field(buf, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        field(buf, "CODE_LINE", line);
+    }
+    if let Some(module_path) = record.module_path() {
+        field(buf, "CODE_MODULE", module_path);
+    }
+    field(buf, "TARGET", record.target());
+    let t = thread::current();
+    match t.name() {
+        Some(name) => field(buf, "THREAD", name),
+        None => field(buf, "THREAD", format_args!("{:?}", t.id())),
+    }
+    let _ = crate::kv::for_each(record, |k, v| {
+        field(buf, FieldName(k.as_str()), v);
+        Ok(())
+    });
+    buf.set_reserved(0);
+    buf.write_bytes(b"MESSAGE\n")?;
+    let len_pos = buf.len();
+    buf.write_bytes(&[0; 8])?;
+    let start = buf.len();
+    let result = write!(buf, "{}", record.args());
+    let len = (buf.len() - start) as u64;
+    buf.overwrite(len_pos, &len.to_le_bytes());
+    result
+}
+
+/// Returns the `PRIORITY` field, mapping levels as in `Format::GoogleSystemd`.
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::Error if crate::fatal::is_fatal() => "PRIORITY=2\n", // LOG_CRIT
+        Level::Error => "PRIORITY=3\n",                             // LOG_ERR
+        Level::Warn => "PRIORITY=4\n",                              // LOG_WARNING
+        Level::Info => "PRIORITY=5\n",                              // LOG_NOTICE
+        Level::Debug => "PRIORITY=6\n",                             // LOG_INFO
+        Level::Trace => "PRIORITY=7\n",                             // LOG_DEBUG
+    }
+}
+
+/// Writes a field in the binary-safe form (`NAME\n`, 64-bit little-endian length, value, `\n`)
+/// if it fits entirely; otherwise writes nothing.
+fn field(buf: &mut EntryBuf<Writing>, name: impl Display, value: impl Display) {
+    let mark = buf.len();
+    let result = (|| {
+        write!(buf, "{}", name)?;
+        buf.write_bytes(b"\n")?;
+        let len_pos = buf.len();
+        buf.write_bytes(&[0; 8])?;
+        let start = buf.len();
+        write!(buf, "{}", value)?;
+        let len = (buf.len() - start) as u64;
+        buf.overwrite(len_pos, &len.to_le_bytes());
+        buf.write_bytes(b"\n")
+    })();
+    if result.is_err() {
+        buf.rollback(mark);
+    }
+}
+
+/// Field names with special meaning: those written by `write` itself and the other well-known
+/// fields of `systemd.journal-fields(7)` which a client may send.
+const RESERVED: &[&str] = &[
+    "CODE_FILE",
+    "CODE_FUNC",
+    "CODE_LINE",
+    "CODE_MODULE",
+    "DOCUMENTATION",
+    "ERRNO",
+    "INVOCATION_ID",
+    "MESSAGE",
+    "MESSAGE_ID",
+    "PRIORITY",
+    "SYSLOG_FACILITY",
+    "SYSLOG_IDENTIFIER",
+    "SYSLOG_PID",
+    "SYSLOG_RAW",
+    "SYSLOG_TIMESTAMP",
+    "TARGET",
+    "THREAD",
+    "TID",
+    "USER_INVOCATION_ID",
+];
+
+/// Formats a key-value's key as a valid journal field name.
+///
+/// Journal field names consist of uppercase ASCII letters, digits, and underscores; must not
+/// start with a digit or underscore (which denotes trusted fields); and are at most 64 bytes.
+/// Thus `camera_id` becomes `CAMERA_ID` and `_x` becomes `KV_X`. Keys which would map to a
+/// `RESERVED` name are also prefixed, so `message` becomes `KV_MESSAGE` rather than
+/// duplicating the real `MESSAGE`.
+struct FieldName<'a>(&'a str);
+
+/// Maps a key character to a field name character.
+fn field_char(c: char) -> char {
+    if c.is_ascii_alphanumeric() {
+        c.to_ascii_uppercase()
+    } else {
+        '_'
+    }
+}
+
+impl Display for FieldName<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const MAX_LEN: usize = 64;
+        let mut len = 0;
+        if !self.0.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            f.write_str("KV")?;
+            len += 2;
+        } else if RESERVED
+            .iter()
+            .any(|r| self.0.chars().map(field_char).eq(r.chars()))
+        {
+            f.write_str("KV_")?;
+            len += 3;
+        }
+        for c in self.0.chars().take(MAX_LEN - len) {
+            f.write_char(field_char(c))?;
+        }
+        Ok(())
+    }
+}
+
+/// A socket for sending entries to journald.
+pub(crate) struct Socket {
+    sock: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Socket {
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
+        Ok(Socket {
+            sock: UnixDatagram::unbound()?,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Sends a single entry as written by `write`.
+    ///
+    /// Entries too large for a datagram are passed via a sealed memfd instead.
+    pub(crate) fn send(&self, entry: &[u8]) -> io::Result<()> {
+        match self.sock.send_to(entry, &self.path) {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.raw_os_error() == Some(libc::EMSGSIZE)
+                    || e.raw_os_error() == Some(libc::ENOBUFS) =>
+            {
+                self.send_memfd(entry)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends `entry` by writing it to a sealed memfd and passing the fd via `SCM_RIGHTS`.
+    fn send_memfd(&self, entry: &[u8]) -> io::Result<()> {
+        // SAFETY: the name is a valid C string.
+        let fd = unsafe {
+            libc::memfd_create(
+                b"mylog-journal\0".as_ptr() as *const libc::c_char,
+                libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just created and is owned by nothing else; `File` closes it on drop.
+        let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+        file.write_all(entry)?;
+        const SEALS: libc::c_int =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        // SAFETY: `fd` is a valid memfd.
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, SEALS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: all-zero is a valid `sockaddr_un`.
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let path = self.path.as_os_str().as_bytes();
+        if path.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "journald socket path too long",
+            ));
+        }
+        for (dst, &src) in addr.sun_path.iter_mut().zip(path) {
+            *dst = src as libc::c_char;
+        }
+        let addr_len = std::mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+
+        // A buffer large enough and suitably aligned for one `cmsghdr` carrying one fd.
+        let mut control = [0u64; 4];
+        // SAFETY: `CMSG_SPACE` is a pure computation.
+        let control_len = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as _) };
+        assert!(control_len as usize <= std::mem::size_of_val(&control));
+
+        // SAFETY: all-zero is a valid `msghdr`; the pointers stored within outlive the
+        // `sendmsg` call, and the control buffer is sized and aligned for the header written.
+        unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = &mut addr as *mut libc::sockaddr_un as *mut libc::c_void;
+            msg.msg_namelen = addr_len as libc::socklen_t;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control_len as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as _) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, fd);
+            if libc::sendmsg(self.sock.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use std::io::{Read as _, Seek as _};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixDatagram;
+
+    fn format(record: &log::Record) -> Vec<u8> {
+        crate::testutil::format_bytes(crate::MAX_ENTRY_SIZE, |buf| super::write(record, buf))
+    }
+
+    /// Returns the binary-safe encoding of a field.
+    fn binary_field(name: &str, value: &str) -> Vec<u8> {
+        let mut f = format!("{}\n", name).into_bytes();
+        f.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        f.extend_from_slice(value.as_bytes());
+        f.push(b'\n');
+        f
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    /// Binds a stand-in for journald's socket at a unique temporary path.
+    fn stand_in(name: &str) -> (UnixDatagram, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "mylog-journald-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        (UnixDatagram::bind(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn fields() {
+        let kvs = [("camera_id", 3)];
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("multi\nline"))
+                .level(log::Level::Warn)
+                .target("foo::bar")
+                .file(Some("src/bar.rs"))
+                .line(Some(42))
+                .key_values(&kvs)
+                .build(),
+        );
+        assert!(entry.starts_with(b"PRIORITY=4\n"));
+        assert!(contains(&entry, &binary_field("CODE_FILE", "src/bar.rs")));
+        assert!(contains(&entry, &binary_field("CODE_LINE", "42")));
+        assert!(contains(&entry, &binary_field("TARGET", "foo::bar")));
+        assert!(contains(&entry, &binary_field("CAMERA_ID", "3")));
+        assert!(entry.ends_with(&binary_field("MESSAGE", "multi\nline")));
+    }
+
+    #[test]
+    fn field_names() {
+        assert_eq!(super::FieldName("camera.id").to_string(), "CAMERA_ID");
+        assert_eq!(super::FieldName("_trusted").to_string(), "KV_TRUSTED");
+        assert_eq!(super::FieldName("9").to_string(), "KV9");
+        assert_eq!(super::FieldName(&"a".repeat(100)).to_string().len(), 64);
+        assert_eq!(super::FieldName("message").to_string(), "KV_MESSAGE");
+        assert_eq!(super::FieldName("code.file").to_string(), "KV_CODE_FILE");
+        assert_eq!(super::FieldName("messages").to_string(), "MESSAGES");
+    }
+
+    #[test]
+    fn reserved_keys() {
+        let kvs = [("priority", "0"), ("message", "spoofed")];
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("real"))
+                .level(log::Level::Info)
+                .target("foo")
+                .key_values(&kvs)
+                .build(),
+        );
+        assert!(entry.starts_with(b"PRIORITY=5\n"));
+        assert!(!contains(&entry, b"\nPRIORITY"));
+        assert!(contains(&entry, &binary_field("KV_PRIORITY", "0")));
+        assert!(contains(&entry, &binary_field("KV_MESSAGE", "spoofed")));
+        let messages = entry.windows(9).filter(|w| w == b"\nMESSAGE\n").count();
+        assert_eq!(messages, 1);
+        assert!(entry.ends_with(&binary_field("MESSAGE", "real")));
+    }
+
+    #[test]
+    fn send() {
+        let (server, path) = stand_in("send");
+        let client = Socket::new(&path).unwrap();
+        client.send(b"PRIORITY=5\nMESSAGE=hi\n").unwrap();
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"PRIORITY=5\nMESSAGE=hi\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests that an entry too large for a datagram is passed via memfd.
+    #[test]
+    fn send_memfd() {
+        let (server, path) = stand_in("memfd");
+        let client = Socket::new(&path).unwrap();
+
+        // Shrink the send buffer so a modest entry gets `EMSGSIZE`.
+        let sndbuf: libc::c_int = 4096;
+        let ret = unsafe {
+            libc::setsockopt(
+                client.sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &sndbuf as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+        let entry = format!("MESSAGE={}\n", "x".repeat(1 << 16));
+        client.send(entry.as_bytes()).unwrap();
+
+        // Receive the fd.
+        let mut control = [0u64; 4];
+        let fd = unsafe {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = std::mem::size_of_val(&control) as _;
+            let n = libc::recvmsg(server.as_raw_fd(), &mut msg, 0);
+            assert_eq!(n, 0);
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            assert!(!cmsg.is_null());
+            assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+            std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int)
+        };
+        let mut f = unsafe { std::fs::File::from_raw_fd(fd) };
+        f.rewind().unwrap();
+        let mut received = String::new();
+        f.read_to_string(&mut received).unwrap();
+        assert_eq!(received, entry);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+//! The [JSON Lines](https://jsonlines.org/) format: one JSON object per entry.
+
+use crate::entry_buf::{EntryBuf, Writing};
+use log::Record;
+use std::fmt::{Display, Write as _};
+use std::thread;
+
+/// Appended after a truncated message to close the string and the object.
+///
+/// Space for this is reserved throughout the entry, so it always fits.
+const TRUNCATED_IN_STRING: &str = "\",\"truncated\":true}";
+
+/// Writes `record` as a single-line JSON object.
+///
+/// Typical entry (wrapped here for readability):
+/// ```text
+/// {"timestamp":"2021-03-08T21:31:24.255123-08:00","level":"info","thread":"main",
+///  "target":"moonfire_nvr","module_path":"moonfire_nvr","file":"src/main.rs","line":42,
+///  "message":"Success."}
+/// ```
+///
+/// Key-values attached to the record are written as a nested `"fields"` object, omitted when
+/// empty.
+///
+/// If the entry would exceed the maximum entry size, fields which don't fit are dropped, the
+/// message is shortened as necessary, and a `"truncated":true` field is added, so the line is
+/// still valid JSON.
+pub(crate) fn write(record: &Record, buf: &mut EntryBuf<Writing>) -> Result<(), std::fmt::Error> {
+    buf.set_reserved(TRUNCATED_IN_STRING.len());
+    let mut obj = Object::open(buf)?;
+    let result = write_fields(record, &mut obj);
+    let (in_string, first, truncated) = (obj.in_string, obj.first, obj.truncated);
+    buf.set_reserved(0);
+    match result {
+        Ok(()) if !truncated => buf.write_str("}"),
+        _ if in_string => buf.write_str(TRUNCATED_IN_STRING),
+        _ if first => buf.write_str("\"truncated\":true}"),
+        _ => buf.write_str(",\"truncated\":true}"),
+    }
+}
+
+fn write_fields(record: &Record, obj: &mut Object) -> Result<(), std::fmt::Error> {
+    const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
+    obj.str_field("timestamp", jiff::Zoned::now().strftime(TIME_FORMAT))?;
+    obj.str_field("level", level_str(record.level()))?;
+    let t = thread::current();
+    match t.name() {
+        Some(name) => obj.str_field("thread", name)?,
+        None => obj.str_field("thread", format_args!("{:?}", t.id()))?,
+    }
+    obj.str_field("target", record.target())?;
+    if let Some(module_path) = record.module_path() {
+        obj.str_field("module_path", module_path)?;
+    }
+    if let Some(file) = record.file() {
+        obj.str_field("file", file)?;
+    }
+    if let Some(line) = record.line() {
+        obj.raw_field("line", line)?;
+    }
+    if record.key_values().count() > 0 {
+        obj.fields_field("fields", record)?;
+    }
+    obj.message_field("message", record.args())
+}
+
+/// Returns the lowercase name of a level, as used in machine-readable formats.
+///
+/// Entries from `fatal!` are `fatal` rather than `error`.
+pub(crate) fn level_str(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error if crate::fatal::is_fatal() => "fatal",
+        log::Level::Error => "error",
+        log::Level::Warn => "warn",
+        log::Level::Info => "info",
+        log::Level::Debug => "debug",
+        log::Level::Trace => "trace",
+    }
+}
+
+/// A JSON object being written to an `EntryBuf`.
+///
+/// Each field is written entirely or not at all; a field which doesn't fit is omitted (setting
+/// `truncated`) so that later fields, notably the message, still have a chance. The final
+/// message field may instead be cut off (at an escape sequence boundary) with `in_string` set.
+struct Object<'a, 'b> {
+    buf: &'a mut EntryBuf<'b, Writing>,
+
+    /// True iff no fields have been written yet.
+    first: bool,
+
+    /// True iff truncation happened within a string value which needs to be closed.
+    in_string: bool,
+
+    /// True iff a field was omitted because it didn't fit.
+    truncated: bool,
+}
+
+impl<'a, 'b> Object<'a, 'b> {
+    fn open(buf: &'a mut EntryBuf<'b, Writing>) -> Result<Self, std::fmt::Error> {
+        buf.write_whole("{")?;
+        Ok(Object {
+            buf,
+            first: true,
+            in_string: false,
+            truncated: false,
+        })
+    }
+
+    /// Writes the (comma-separated) key and colon.
+    fn key(&mut self, key: &str) -> Result<(), std::fmt::Error> {
+        if !self.first {
+            self.buf.write_str(",")?;
+        }
+        self.buf.write_str("\"")?;
+        self.buf.write_str(key)?;
+        self.buf.write_str("\":")
+    }
+
+    /// Writes a field with a value which is already valid JSON, such as a number.
+    fn raw_field(&mut self, key: &str, value: impl Display) -> Result<(), std::fmt::Error> {
+        let mark = self.buf.len();
+        let result = self.key(key).and_then(|()| write!(self.buf, "{}", value));
+        self.finish_field(mark, result)
+    }
+
+    /// Writes a field with a string value, escaping it as necessary.
+    fn str_field(&mut self, key: &str, value: impl Display) -> Result<(), std::fmt::Error> {
+        let mark = self.buf.len();
+        let result = self.key(key).and_then(|()| {
+            self.buf.write_str("\"")?;
+            write!(Escaper(self.buf), "{}", value)?;
+            self.buf.write_str("\"")
+        });
+        self.finish_field(mark, result)
+    }
+
+    /// Writes a nested object holding the key-values attached to `record`.
+    ///
+    /// Numbers, booleans, and nulls keep their JSON types; other values are written as strings.
+    fn fields_field(&mut self, key: &str, record: &Record) -> Result<(), std::fmt::Error> {
+        let mark = self.buf.len();
+        let result = self.key(key).and_then(|()| {
+            self.buf.write_str("{")?;
+            let mut first = true;
+            crate::kv::for_each(record, |k, v| {
+                if !first {
+                    self.buf.write_str(",")?;
+                }
+                first = false;
+                self.buf.write_str("\"")?;
+                write!(Escaper(self.buf), "{}", k)?;
+                self.buf.write_str("\":")?;
+                v.visit(ValueWriter(self.buf)).map_err(|_| std::fmt::Error)
+            })?;
+            self.buf.write_str("}")
+        });
+        self.finish_field(mark, result)
+    }
+
+    /// Writes a string field which may be cut off rather than omitted on truncation.
+    fn message_field(&mut self, key: &str, value: impl Display) -> Result<(), std::fmt::Error> {
+        let mark = self.buf.len();
+        if let Err(e) = self.key(key).and_then(|()| self.buf.write_str("\"")) {
+            self.buf.rollback(mark);
+            return Err(e);
+        }
+        self.first = false;
+        if write!(Escaper(self.buf), "{}", value).is_err() {
+            self.in_string = true;
+            return Err(std::fmt::Error);
+        }
+        self.buf.write_str("\"").map_err(|e| {
+            self.in_string = true;
+            e
+        })
+    }
+
+    /// Completes a field, omitting it if it didn't fit.
+    fn finish_field(
+        &mut self,
+        mark: usize,
+        result: Result<(), std::fmt::Error>,
+    ) -> Result<(), std::fmt::Error> {
+        if result.is_err() {
+            self.buf.rollback(mark);
+            self.truncated = true;
+            return Ok(());
+        }
+        self.first = false;
+        Ok(())
+    }
+}
+
+/// Writes a key-value's value as the closest JSON type.
+struct ValueWriter<'a, 'b>(&'a mut EntryBuf<'b, Writing>);
+
+impl ValueWriter<'_, '_> {
+    fn raw(&mut self, value: impl Display) -> Result<(), log::kv::Error> {
+        Ok(write!(self.0, "{}", value)?)
+    }
+
+    fn string(&mut self, value: impl Display) -> Result<(), log::kv::Error> {
+        self.0.write_str("\"")?;
+        write!(Escaper(self.0), "{}", value)?;
+        Ok(self.0.write_str("\"")?)
+    }
+}
+
+impl<'v> log::kv::VisitValue<'v> for ValueWriter<'_, '_> {
+    fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+        self.string(value)
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+        self.raw("null")
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
+        self.raw(value)
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error> {
+        self.raw(value)
+    }
+
+    fn visit_u128(&mut self, value: u128) -> Result<(), log::kv::Error> {
+        self.raw(value)
+    }
+
+    fn visit_i128(&mut self, value: i128) -> Result<(), log::kv::Error> {
+        self.raw(value)
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), log::kv::Error> {
+        if value.is_finite() {
+            self.raw(value)
+        } else {
+            // JSON has no representation for NaN or infinities.
+            self.string(value)
+        }
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error> {
+        self.raw(value)
+    }
+}
+
+/// Escapes JSON string contents as they're written.
+///
+/// Each escape sequence is written entirely or not at all.
+/// The result is also suitable for quoted `logfmt` values.
+pub(crate) struct Escaper<'a, 'b>(pub(crate) &'a mut EntryBuf<'b, Writing>);
+
+impl std::fmt::Write for Escaper<'_, '_> {
+    fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
+        let mut start = 0;
+        for (i, b) in s.bytes().enumerate() {
+            let escaped = match b {
+                b'"' => "\\\"",
+                b'\\' => "\\\\",
+                b'\n' => "\\n",
+                b'\r' => "\\r",
+                b'\t' => "\\t",
+                0..=0x1f => "",
+                _ => continue,
+            };
+            self.0.write_str(&s[start..i])?;
+            start = i + 1;
+            if escaped.is_empty() {
+                let mut tmp = [0u8; 6];
+                self.0.write_whole(unicode_escape(b, &mut tmp))?;
+            } else {
+                self.0.write_whole(escaped)?;
+            }
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+/// Formats a control character as a `\u00XX` escape.
+fn unicode_escape(b: u8, tmp: &mut [u8; 6]) -> &str {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    *tmp = [
+        b'\\',
+        b'u',
+        b'0',
+        b'0',
+        HEX[usize::from(b >> 4)],
+        HEX[usize::from(b & 0xf)],
+    ];
+    std::str::from_utf8(&tmp[..]).expect("escape is ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testutil, MAX_ENTRY_SIZE};
+
+    fn format(record: &log::Record) -> String {
+        testutil::format(MAX_ENTRY_SIZE, |buf| super::write(record, buf))
+    }
+
+    /// Returns the entry with the variable timestamp and thread fields removed.
+    fn strip_variable(entry: &str) -> String {
+        let start = entry.find("\"timestamp\":").unwrap();
+        let end = entry.find("\"target\":").unwrap();
+        format!("{}{}", &entry[..start], &entry[end..])
+    }
+
+    #[test]
+    fn escaping() {
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("quote \" backslash \\ newline \n bell \x07 é"))
+                .level(log::Level::Info)
+                .target("foo::bar")
+                .file(Some("src/bar.rs"))
+                .line(Some(42))
+                .build(),
+        );
+        assert_eq!(
+            strip_variable(&entry),
+            "{\"target\":\"foo::bar\",\"file\":\"src/bar.rs\",\"line\":42,\
+             \"message\":\"quote \\\" backslash \\\\ newline \\n bell \\u0007 é\"}\n"
+        );
+        assert!(entry.contains("\"level\":\"info\""));
+    }
+
+    #[test]
+    fn key_values() {
+        let name = "front door";
+        let kvs: [(&str, log::kv::Value); 4] = [
+            ("camera_id", 3.into()),
+            ("name", log::kv::Value::from_display(&name)),
+            ("ok", true.into()),
+            ("ratio", f64::NAN.into()),
+        ];
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("hi"))
+                .level(log::Level::Info)
+                .target("foo")
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(
+            strip_variable(&entry),
+            "{\"target\":\"foo\",\"fields\":{\"camera_id\":3,\"name\":\"front door\",\
+             \"ok\":true,\"ratio\":\"NaN\"},\"message\":\"hi\"}\n"
+        );
+    }
+
+    #[test]
+    fn truncated_message() {
+        let msg = "\"".repeat(MAX_ENTRY_SIZE);
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("{}", msg))
+                .level(log::Level::Warn)
+                .target("foo")
+                .build(),
+        );
+        assert!(entry.len() <= MAX_ENTRY_SIZE);
+        assert!(entry.ends_with("\\\"\",\"truncated\":true}\n"), "{}", entry);
+    }
+
+    #[test]
+    fn truncated_field() {
+        let target = "t".repeat(MAX_ENTRY_SIZE);
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("msg"))
+                .level(log::Level::Warn)
+                .target(&target)
+                .build(),
+        );
+        assert!(entry.len() <= MAX_ENTRY_SIZE);
+        assert!(
+            entry.ends_with(",\"message\":\"msg\",\"truncated\":true}\n"),
+            "{}",
+            entry
+        );
+        assert!(!entry.contains("\"target\""), "{}", entry);
+    }
+
+    #[test]
+    fn truncated_key_value() {
+        let big = "x".repeat(500);
+        let kvs = [("big", &big[..])];
+        let record = log::Record::builder()
+            .args(format_args!("disk full"))
+            .level(log::Level::Warn)
+            .target("probe")
+            .file(Some("src/probe.rs"))
+            .line(Some(15))
+            .key_values(&kvs)
+            .build();
+        let entry = testutil::format(200, |buf| super::write(&record, buf));
+        assert!(entry.len() <= 200);
+        assert!(
+            strip_variable(&entry).ends_with(
+                "\"target\":\"probe\",\"file\":\"src/probe.rs\",\"line\":15,\
+                 \"message\":\"disk full\",\"truncated\":true}\n"
+            ),
+            "{}",
+            entry
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Rendering of structured key-values attached via `log`'s `kv` feature, e.g.
+//! `info!(camera_id = id; "...")`.
+
+use crate::entry_buf::{EntryBuf, Writing};
+use log::kv::{Key, Value, VisitSource};
+use log::Record;
+
+/// Calls `f` for each key-value pair attached to `record`, stopping at the first error.
+pub(crate) fn for_each<'kvs>(
+    record: &'kvs Record,
+    f: impl FnMut(Key<'kvs>, Value<'kvs>) -> Result<(), std::fmt::Error>,
+) -> Result<(), std::fmt::Error> {
+    struct Visitor<F>(F);
+
+    impl<'kvs, F> VisitSource<'kvs> for Visitor<F>
+    where
+        F: FnMut(Key<'kvs>, Value<'kvs>) -> Result<(), std::fmt::Error>,
+    {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+            Ok((self.0)(key, value)?)
+        }
+    }
+
+    record
+        .key_values()
+        .visit(&mut Visitor(f))
+        .map_err(|_| std::fmt::Error)
+}
+
+/// Appends ` key=value` for each key-value pair, sanitizing keys and quoting values as in the
+/// logfmt format.
+///
+/// This is used by the human-oriented formats, after the message.
+pub(crate) fn write_pairs(
+    record: &Record,
+    buf: &mut EntryBuf<Writing>,
+) -> Result<(), std::fmt::Error> {
+    for_each(record, |k, v| {
+        use std::fmt::Write as _;
+        buf.write_str(" ")?;
+        crate::logfmt::write_key(buf, k.as_str())?;
+        buf.write_str("=")?;
+        crate::logfmt::write_value(buf, v)
+    })
+}
@@ -1,31 +1,59 @@
 //! A simple stderr-based logger which supports a couple formats and asynchronous operation.
 
+mod async_buf;
+mod control;
+mod crash;
 mod entry_buf;
+mod fatal;
+mod file;
+#[cfg(target_os = "linux")]
+mod journald;
+mod json;
+mod kv;
+mod logfmt;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+))]
+mod sighup;
+mod sink;
 mod spec;
+#[cfg(target_os = "linux")]
+mod spec_file;
+mod syslog;
+mod template;
+#[cfg(test)]
+mod testutil;
 
+use crate::async_buf::{AsyncBuf, Shard, ShardInner};
 use crate::entry_buf::EntryBuf;
+use arc_swap::ArcSwap;
 use log::{Level, Metadata, Record};
-use spec::Specification;
+use sink::Sinks;
 use std::fmt::Write as _;
 use std::io::Write as _;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
 
-/// The maximum number of bytes of a single log entry including the trailing `\n`.
-///
-/// Must be at least one (to fit the trailing `\n`) and must fit within the program stack.
-/// Thus it can be safely assumed it is less than `isize::max()` as well.
-///
-/// If a log call tries to write more than this size, the entry will be truncated at a UTF-8
-/// boundary. Truncated entries will always end in `\n`.
+#[doc(hidden)]
+pub use crate::fatal::__fatal;
+pub use crate::file::{LogFile, RotationInterval};
+pub use crate::sink::Sink;
+pub use crate::spec::{SpecError, Specification};
+pub use crate::syslog::{Facility, Syslog, SyslogProtocol};
+pub use crate::template::{Template, TemplateError};
+
+/// The default maximum number of bytes of a single log entry including the trailing `\n`;
+/// see `Builder::max_entry_size`.
 const MAX_ENTRY_SIZE: usize = 1 << 16;
 
-/// The size of the (heap-allocated) asynchronous buffer.
-///
-/// Twice this size will be allocated in total due to a double-buffering scheme.
-///
-/// Entries are copied to this buffer atomically, so this must be at least `MAX_ENTRY_SIZE` or
-/// `Logger::log` could block forever waiting for space.
+/// The default size of the (heap-allocated) asynchronous buffer; see
+/// `Builder::async_buffer_size`.
 const ASYNC_BUF_SIZE: usize = 1 << 20;
 
 /// The format of logged messages.
@@ -39,6 +67,7 @@ pub enum Format {
     /// I20210308 21:31:24.255 main moonfire_nvr] Success.
     /// LYYYYmmdd HH:MM:SS.FFF TTTT PPPPPPPPPPPP] ...
     /// L    = level:
+    ///        F = fatal!
     ///        E = error!
     ///        W = warn!
     ///        I = info!
@@ -51,9 +80,10 @@ pub enum Format {
     /// MM   = minute
     /// SS   = second
     /// FFF  = fractional portion of the second
-    /// TTTT = thread name (if set) or tid (otherwise)
-    /// PPPP = log target (usually a module path)
-    /// ...  = the message supplied to the log macro.
+    /// TTTT = thread name (if set) or tid (otherwise); see `ThreadColumn`
+    /// PPPP = log target (usually a module path) and/or source file and line; see `Location`
+    /// ...  = the message supplied to the log macro, followed by ` key=value` for each
+    ///        key-value attached to the record (`info!(camera_id = id; "...")`)
     /// ```
     Google,
 
@@ -68,8 +98,11 @@ pub enum Format {
     /// <5>main moonfire_nvr] Success.
     /// ```
     ///
+    /// As in `Google`, key-values attached to the record follow the message as ` key=value`.
+    ///
     /// The supported log levels are as follows:
     /// ```text
+    /// <2> = SD_CRIT    = fatal!
     /// <3> = SD_ERR     = error!
     /// <4> = SD_WARNING = warn!
     /// <5> = SD_NOTICE  = info!
@@ -77,6 +110,42 @@ pub enum Format {
     /// <7> = SD_DEBUG   = trace!
     /// ```
     GoogleSystemd,
+
+    /// [JSON Lines](https://jsonlines.org/): one JSON object per entry, for log shippers.
+    ///
+    /// This log format ignores `ColorMode`.
+    ///
+    /// Typical entry (wrapped here for readability):
+    /// ```text
+    /// {"timestamp":"2021-03-08T21:31:24.255123-08:00","level":"info","thread":"main",
+    ///  "target":"moonfire_nvr","module_path":"moonfire_nvr","file":"src/main.rs","line":42,
+    ///  "message":"Success."}
+    /// ```
+    ///
+    /// `module_path`, `file`, and `line` are omitted when not known. Key-values attached to the
+    /// record are written as a nested `"fields"` object, omitted when empty. Truncated entries are still
+    /// valid JSON; they end with a `"truncated":true` field.
+    Json,
+
+    /// [logfmt](https://brandur.org/logfmt): space-separated `key=value` pairs, as expected by
+    /// Grafana Loki and friends.
+    ///
+    /// This log format ignores `ColorMode`.
+    ///
+    /// Typical entry:
+    /// ```text
+    /// ts=2021-03-08T21:31:24.255123-08:00 level=info thread=main target=moonfire_nvr msg="Success."
+    /// ```
+    ///
+    /// Key-values attached to the record appear as additional pairs before `msg`.
+    /// Values are quoted and escaped as necessary. Truncated entries end with `truncated=true`.
+    Logfmt,
+
+    /// A custom layout, as given by a pattern string such as
+    /// `"{level:1}{time:%H:%M:%S%.6f} {tid} {target}:{line}] {msg} {kv}"`; see `Template`.
+    ///
+    /// This log format ignores `ColorMode`.
+    Template(Template),
 }
 
 impl std::str::FromStr for Format {
@@ -86,11 +155,211 @@ impl std::str::FromStr for Format {
         match s {
             "google" => Ok(Format::Google),
             "google-systemd" => Ok(Format::GoogleSystemd),
+            "json" => Ok(Format::Json),
+            "logfmt" => Ok(Format::Logfmt),
             _ => Err(()),
         }
     }
 }
 
+/// Which source location `Format::Google` and `Format::GoogleSystemd` write before the `]`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Location {
+    /// The log target, usually a module path: `moonfire_nvr::db]`.
+    #[default]
+    Target,
+
+    /// The source file and line, as glog does: `src/db.rs:42]`. The target is written instead
+    /// if the file isn't known.
+    FileLine,
+
+    /// The target, then the file and line: `moonfire_nvr::db src/db.rs:42]`.
+    Both,
+}
+
+impl std::str::FromStr for Location {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "target" => Ok(Location::Target),
+            "file-line" => Ok(Location::FileLine),
+            "both" => Ok(Location::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What `Format::Google` and `Format::GoogleSystemd` write to identify the logging thread.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ThreadColumn {
+    /// The thread's name if set, or its `std::thread::ThreadId` otherwise: `main`,
+    /// `ThreadId(7)`.
+    #[default]
+    Name,
+
+    /// The kernel thread ID, as in glog and as shown by `top -H`, `perf`, and
+    /// `/proc/<pid>/task`: `12345`. On platforms other than Linux, the `ThreadId` is written
+    /// instead.
+    Tid,
+
+    /// The thread's name if set, then the kernel thread ID: `main:12345`, or `12345` for an
+    /// unnamed thread.
+    NameAndTid,
+}
+
+impl std::str::FromStr for ThreadColumn {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(ThreadColumn::Name),
+            "tid" => Ok(ThreadColumn::Tid),
+            "name-and-tid" => Ok(ThreadColumn::NameAndTid),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Writes the calling thread's kernel thread ID, which is cached to avoid a syscall per entry.
+#[cfg(target_os = "linux")]
+fn write_tid(out: &mut dyn std::fmt::Write) -> Result<(), std::fmt::Error> {
+    thread_local! {
+        static TID: std::cell::Cell<libc::pid_t> = const { std::cell::Cell::new(0) };
+    }
+
+    // SAFETY: `gettid` has no preconditions.
+    let gettid = || unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+    let tid = TID
+        .try_with(|c| {
+            if c.get() == 0 {
+                c.set(gettid());
+            }
+            c.get()
+        })
+        .unwrap_or_else(|_| gettid());
+    write!(out, "{}", tid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_tid(out: &mut dyn std::fmt::Write) -> Result<(), std::fmt::Error> {
+    write!(out, "{:?}", thread::current().id())
+}
+
+/// Writes entries in a custom format; see `Builder::formatter`.
+///
+/// Entries are still written to a buffer of `Builder::max_entry_size` bytes (truncated at a
+/// UTF-8 boundary if necessary) and batched in asynchronous mode, as with the built-in formats.
+///
+/// ```
+/// use std::fmt::Write as _;
+///
+/// struct House;
+///
+/// impl mylog::Formatter for House {
+///     fn format(
+///         &self,
+///         record: &log::Record,
+///         ctx: &mylog::FormatContext,
+///         out: &mut dyn std::fmt::Write,
+///     ) -> std::fmt::Result {
+///         write!(out, "[{}] ", record.level())?;
+///         ctx.write_location(record, out)?;
+///         write!(out, ": {}", record.args())
+///     }
+/// }
+///
+/// let h = mylog::Builder::new().formatter(House).build();
+/// ```
+pub trait Formatter: Send + Sync + 'static {
+    /// Writes `record` to `out`, without a trailing newline; one is added afterward.
+    fn format(
+        &self,
+        record: &Record,
+        ctx: &FormatContext,
+        out: &mut dyn std::fmt::Write,
+    ) -> std::fmt::Result;
+}
+
+/// Options which affect how a sink's entries are formatted.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FormatContext {
+    pub(crate) use_color: bool,
+    pub(crate) location: Location,
+    pub(crate) thread: ThreadColumn,
+    pub(crate) file_basename: bool,
+}
+
+impl FormatContext {
+    /// Returns true if the entry should use color, as dictated by the sink's `ColorMode`.
+    pub fn use_color(&self) -> bool {
+        self.use_color
+    }
+
+    /// Returns the source location to write, as set by `Builder::location`.
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    /// Returns how to identify the logging thread, as set by `Builder::thread`.
+    pub fn thread(&self) -> ThreadColumn {
+        self.thread
+    }
+
+    /// Returns true if source files should be written without their directory, as set by
+    /// `Builder::file_basename`.
+    pub fn file_basename(&self) -> bool {
+        self.file_basename
+    }
+
+    /// Writes the calling thread's column, as configured by `thread`.
+    pub fn write_thread(&self, out: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        if self.thread == ThreadColumn::Tid {
+            return write_tid(out);
+        }
+        let t = thread::current();
+        match (self.thread, t.name()) {
+            (ThreadColumn::Name, Some(name)) => out.write_str(name),
+            (ThreadColumn::Name, None) => write!(out, "{:?}", t.id()),
+            (ThreadColumn::NameAndTid, Some(name)) => {
+                write!(out, "{}:", name)?;
+                write_tid(out)
+            }
+            (ThreadColumn::Tid, _) | (ThreadColumn::NameAndTid, None) => write_tid(out),
+        }
+    }
+
+    /// Returns the source file of `record`, if known, honoring `file_basename`.
+    pub fn file<'a>(&self, record: &Record<'a>) -> Option<&'a str> {
+        let file = record.file()?;
+        if self.file_basename {
+            return file.rsplit(['/', '\\']).next();
+        }
+        Some(file)
+    }
+
+    /// Writes the source location of `record`, as configured by `location`.
+    pub fn write_location(
+        &self,
+        record: &Record,
+        out: &mut dyn std::fmt::Write,
+    ) -> std::fmt::Result {
+        let file = match (self.location, self.file(record)) {
+            (Location::Target, _) | (_, None) => return out.write_str(record.target()),
+            (Location::Both, Some(f)) => {
+                write!(out, "{} ", record.target())?;
+                f
+            }
+            (Location::FileLine, Some(f)) => f,
+        };
+        out.write_str(file)?;
+        if let Some(line) = record.line() {
+            write!(out, ":{}", line)?;
+        }
+        Ok(())
+    }
+}
+
 fn local_time() -> jiff::civil::DateTime {
     jiff::tz::TimeZone::system().to_datetime(jiff::Timestamp::now())
 }
@@ -98,23 +367,28 @@ fn local_time() -> jiff::civil::DateTime {
 impl Format {
     fn write(
         &self,
-        use_color: bool,
+        ctx: &FormatContext,
         record: &Record,
         buf: &mut EntryBuf<entry_buf::Writing>,
     ) -> Result<(), std::fmt::Error> {
         match *self {
-            Format::Google => Format::write_google(use_color, record, buf),
-            Format::GoogleSystemd => Format::write_google_systemd(record, buf),
+            Format::Google => Format::write_google(ctx, record, buf),
+            Format::GoogleSystemd => Format::write_google_systemd(ctx, record, buf),
+            Format::Json => json::write(record, buf),
+            Format::Logfmt => logfmt::write(record, buf),
+            Format::Template(ref t) => t.write(ctx, record, buf),
         }
     }
 
     fn write_google(
-        use_color: bool,
+        ctx: &FormatContext,
         record: &Record,
         buf: &mut EntryBuf<entry_buf::Writing>,
     ) -> Result<(), std::fmt::Error> {
         const RESET_CODE: &str = "\x1b[0m";
-        let (prefix, suffix) = match (record.level(), use_color) {
+        let (prefix, suffix) = match (record.level(), ctx.use_color) {
+            (Level::Error, true) if fatal::is_fatal() => ("\x1b[31;1mF", RESET_CODE), // bright red
+            (Level::Error, false) if fatal::is_fatal() => ("F", ""),
             (Level::Error, true) => ("\x1b[31;1mE", RESET_CODE), // bright red
             (Level::Error, false) => ("E", ""),
             (Level::Warn, true) => ("\x1b[33;1mW", RESET_CODE), // bright yellow
@@ -124,50 +398,34 @@ impl Format {
             (Level::Trace, _) => ("T", ""),
         };
         const TIME_FORMAT: &str = "%Y%m%d %H:%M:%S%.3f";
-        let t = thread::current();
-        if let Some(name) = t.name() {
-            write!(
-                buf,
-                "{}{} {} {}] {}{}",
-                prefix,
-                local_time().strftime(TIME_FORMAT),
-                name,
-                record.metadata().target(),
-                record.args(),
-                suffix
-            )
-        } else {
-            write!(
-                buf,
-                "{}{} {:?} {}] {}{}",
-                prefix,
-                local_time().strftime(TIME_FORMAT),
-                t.id(),
-                record.metadata().target(),
-                record.args(),
-                suffix
-            )
-        }
+        write!(buf, "{}{} ", prefix, local_time().strftime(TIME_FORMAT))?;
+        ctx.write_thread(buf)?;
+        buf.write_str(" ")?;
+        ctx.write_location(record, buf)?;
+        write!(buf, "] {}", record.args())?;
+        kv::write_pairs(record, buf)?;
+        buf.write_str(suffix)
     }
 
     fn write_google_systemd(
+        ctx: &FormatContext,
         record: &Record,
         buf: &mut EntryBuf<entry_buf::Writing>,
     ) -> Result<(), std::fmt::Error> {
         let level = match record.level() {
-            Level::Error => "<3>", // SD_ERR
-            Level::Warn => "<4>",  // SD_WARNING
-            Level::Info => "<5>",  // SD_NOTICE
-            Level::Debug => "<6>", // SD_INFO
-            Level::Trace => "<7>", // SD_DEBUG
+            Level::Error if fatal::is_fatal() => "<2>", // SD_CRIT
+            Level::Error => "<3>",                      // SD_ERR
+            Level::Warn => "<4>",                       // SD_WARNING
+            Level::Info => "<5>",                       // SD_NOTICE
+            Level::Debug => "<6>",                      // SD_INFO
+            Level::Trace => "<7>",                      // SD_DEBUG
         };
-        let p = record.metadata().target();
-        let t = thread::current();
-        if let Some(name) = t.name() {
-            write!(buf, "{}{} {}] {}", level, name, p, record.args())
-        } else {
-            write!(buf, "{}{:?} {}] {}", level, t.id(), p, record.args())
-        }
+        buf.write_str(level)?;
+        ctx.write_thread(buf)?;
+        buf.write_str(" ")?;
+        ctx.write_location(record, buf)?;
+        write!(buf, "] {}", record.args())?;
+        kv::write_pairs(record, buf)
     }
 }
 
@@ -175,6 +433,34 @@ impl Format {
 pub enum Destination {
     Stderr,
     Stdout,
+
+    /// The systemd journal, via its
+    /// [native protocol](https://systemd.io/JOURNAL_NATIVE_PROTOCOL/).
+    ///
+    /// Each entry is sent as a datagram with the fields `PRIORITY` (mapped from the level as in
+    /// `Format::GoogleSystemd`), `CODE_FILE`, `CODE_LINE`, `CODE_MODULE`, `TARGET`, `THREAD`,
+    /// one field per key-value (with the key uppercased, e.g. `CAMERA_ID`), and `MESSAGE`.
+    /// These can be matched with e.g. `journalctl TARGET=moonfire_nvr::db`.
+    ///
+    /// This destination ignores `Format` and `ColorMode`.
+    #[cfg(target_os = "linux")]
+    Journald,
+
+    /// A syslog daemon, over a Unix socket (typically `/dev/log`), UDP, or TCP.
+    ///
+    /// Severities are mapped from levels as in `Format::GoogleSystemd`. The message body is as in
+    /// `Format::GoogleSystemd`, minus the priority prefix, with key-values appended as
+    /// ` key=value` (RFC 3164) or passed as structured data (RFC 5424).
+    ///
+    /// As with other destinations, I/O happens on the asynchronous logger thread while
+    /// `Handle::async_scope` is active, so a slow or unreachable collector doesn't block callers
+    /// until the buffer fills.
+    ///
+    /// This destination ignores `Format` and `ColorMode`.
+    Syslog(Syslog),
+
+    /// A file, appended to and optionally rotated; see `LogFile`.
+    File(LogFile),
 }
 
 /// Whether to use color.
@@ -203,11 +489,45 @@ impl std::str::FromStr for ColorMode {
     }
 }
 
+/// What to do when logging while the asynchronous buffer is full, e.g. because the
+/// destination is stalled.
+///
+/// Dropped entries are counted; once there's room again, the logger thread writes a synthetic
+/// `N log entries dropped` warning to every sink.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overflow {
+    /// Block until there's room.
+    Block,
+
+    /// Drop the entry being logged.
+    DropNewest,
+
+    /// Drop just enough buffered entries less severe than the entry being logged to make room,
+    /// the least severe and then the oldest first; if that's not possible, drop the entry being
+    /// logged instead.
+    DropLowestSeverity,
+
+    /// Block until there's room or the given time elapses, then drop the entry being logged.
+    BlockTimeout(Duration),
+}
+
 pub struct Builder {
     spec: Option<Specification>,
     fmt: Format,
     dest: Destination,
     color: ColorMode,
+    sinks: Vec<Sink>,
+    #[cfg(target_os = "linux")]
+    spec_file: Option<std::path::PathBuf>,
+    legacy_prefix_matching: bool,
+    overflow: Overflow,
+    max_entry_size: usize,
+    async_buffer_size: usize,
+    async_mode: bool,
+    location: Location,
+    file_basename: bool,
+    thread: ThreadColumn,
+    formatter: Option<Arc<dyn Formatter>>,
     is_test: bool,
 }
 
@@ -218,6 +538,18 @@ impl Builder {
             fmt: Format::Google,
             dest: Destination::Stderr,
             color: ColorMode::Auto,
+            sinks: Vec::new(),
+            #[cfg(target_os = "linux")]
+            spec_file: None,
+            legacy_prefix_matching: false,
+            overflow: Overflow::Block,
+            max_entry_size: MAX_ENTRY_SIZE,
+            async_buffer_size: ASYNC_BUF_SIZE,
+            async_mode: false,
+            location: Location::Target,
+            file_basename: false,
+            thread: ThreadColumn::Name,
+            formatter: None,
             is_test: false,
         }
     }
@@ -228,12 +560,124 @@ impl Builder {
         self
     }
 
+    /// Uses a custom formatter rather than `format`. Like `format`, `destination`, and
+    /// `color`, this is ignored if any sinks are added; see `Sink::formatter`.
+    #[inline]
+    pub fn formatter(mut self, formatter: impl Formatter) -> Self {
+        self.formatter = Some(Arc::new(formatter));
+        self
+    }
+
     #[inline]
     pub fn spec(mut self, spec: &str) -> Self {
         self.spec = Some(Specification::new(spec));
         self
     }
 
+    /// Sets an already-parsed spec, e.g. one checked with `Specification::parse`.
+    #[inline]
+    pub fn specification(mut self, spec: Specification) -> Self {
+        self.spec = Some(spec);
+        self
+    }
+
+    /// Reads the spec from the given file, and watches it (via inotify) for changes, applying
+    /// each as in `Handle::set_spec`.
+    ///
+    /// Directives in the file may be separated by commas and/or whitespace, including newlines.
+    /// A `/filter` suffix runs to the end of the file, so it may contain spaces.
+    ///
+    /// If the file can't be read or parsed, the error is reported on stderr and logged, and the
+    /// previous spec is kept; at startup, the previous spec is the one given to `spec`.
+    ///
+    /// Replacing the file via rename is supported. `try_build` returns an error (and `build`
+    /// panics) if the file's directory can't be watched.
+    #[cfg(target_os = "linux")]
+    #[inline]
+    pub fn spec_file<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.spec_file = Some(path.into());
+        self
+    }
+
+    /// If true, applies `Specification::legacy_prefix_matching` to all specs used by this logger,
+    /// including those of sinks and those later supplied via `Handle::set_spec`.
+    #[inline]
+    pub fn legacy_prefix_matching(mut self, legacy: bool) -> Self {
+        self.legacy_prefix_matching = legacy;
+        self
+    }
+
+    /// Sets what to do when the asynchronous buffer is full; default is `Overflow::Block`.
+    #[inline]
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Sets the maximum number of bytes of a single entry, including the trailing `\n`; default
+    /// is 64 KiB.
+    ///
+    /// Longer entries are truncated at a UTF-8 boundary and still end in `\n`; the JSON and
+    /// logfmt formats mark them as truncated. Each entry is formatted into a buffer of this size
+    /// on the logging thread's stack (in size classes of 1, 4, 16, and 64 KiB) or, if larger than
+    /// 64 KiB, the heap. Threads with small stacks should use a small size.
+    ///
+    /// `try_build` returns an error (and `build` panics) if this is zero or exceeds
+    /// `async_buffer_size`.
+    #[inline]
+    pub fn max_entry_size(mut self, size: usize) -> Self {
+        self.max_entry_size = size;
+        self
+    }
+
+    /// Sets the size of the asynchronous buffer in bytes; default is 1 MiB.
+    ///
+    /// To reduce contention, the buffer is split into up to one shard per CPU (at most 16), each
+    /// used by a subset of threads. When a thread's shard is full, its entries spill into any
+    /// other shard with room before `Overflow` applies, so even a single busy thread can use the
+    /// whole buffer. Entries are copied to a shard whole, so there are no more shards than
+    /// entries of `max_entry_size` which fit, and `try_build` returns an error (and `build`
+    /// panics) if this is less than `max_entry_size`.
+    ///
+    /// Up to three times this size is allocated in total: the shards, the logger thread's spare
+    /// shards (a double-buffering scheme), and a buffer in which it merges them in order.
+    #[inline]
+    pub fn async_buffer_size(mut self, size: usize) -> Self {
+        self.async_buffer_size = size;
+        self
+    }
+
+    /// Sets which source location `Format::Google` and `Format::GoogleSystemd` write, for all
+    /// sinks; default is `Location::Target`.
+    #[inline]
+    pub fn location(mut self, location: Location) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// If true, source locations include only the file's name rather than its full path, e.g.
+    /// `db.rs:42` rather than `src/db.rs:42`; default is false.
+    #[inline]
+    pub fn file_basename(mut self, file_basename: bool) -> Self {
+        self.file_basename = file_basename;
+        self
+    }
+
+    /// Sets what `Format::Google` and `Format::GoogleSystemd` write to identify the logging
+    /// thread, for all sinks; default is `ThreadColumn::Name`.
+    #[inline]
+    pub fn thread(mut self, thread: ThreadColumn) -> Self {
+        self.thread = thread;
+        self
+    }
+
+    /// If true, `Handle::install` also enters asynchronous mode, until `shutdown` is called.
+    #[inline]
+    pub fn async_mode(mut self, async_mode: bool) -> Self {
+        self.async_mode = async_mode;
+        self
+    }
+
     /// If true, use the `print!` and `eprint!` macros instead of `std::io::stdout` and `std::io::stderr`,
     /// and always write synchronously.
     ///
@@ -258,32 +702,118 @@ impl Builder {
         self
     }
 
+    /// Adds a sink, which has its own destination, format, color mode, and optionally spec.
+    ///
+    /// If any sinks are added, they replace the single sink described by `destination`,
+    /// `format`, `formatter`, and `color`. Each entry is formatted once per distinct format, regardless of
+    /// how many sinks use it. At most 64 sinks are supported.
+    ///
+    /// ```no_run
+    /// use mylog::{Builder, ColorMode, Destination, Format, LogFile, Sink};
+    /// let h = Builder::new()
+    ///     .spec("debug")
+    ///     .sink(Sink::new(Destination::Stderr).color(ColorMode::Auto).spec("info"))
+    ///     .sink(Sink::new(Destination::File(LogFile::new("/var/log/foo.json"))).format(Format::Json))
+    ///     .sink(Sink::new(Destination::Journald).spec("warn"))
+    ///     .build();
+    /// ```
+    #[inline]
+    pub fn sink(mut self, sink: Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Builds the logger.
+    ///
+    /// Panics on any error `try_build` would return.
     pub fn build(self) -> Handle {
-        let use_color = if self.fmt == Format::GoogleSystemd || self.color == ColorMode::Never {
-            false
-        } else if self.color == ColorMode::Always {
-            true
+        match self.try_build() {
+            Ok(h) => h,
+            Err(e) => panic!("unable to build logger: {}", e),
+        }
+    }
+
+    /// Builds the logger, returning an error on invalid settings (see `max_entry_size` and
+    /// `async_buffer_size`), if a destination can't be opened, or if the spec file can't be
+    /// watched.
+    pub fn try_build(self) -> std::io::Result<Handle> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        if self.max_entry_size == 0 {
+            return Err(invalid("max_entry_size must be at least 1".to_owned()));
+        }
+        if self.async_buffer_size < self.max_entry_size {
+            return Err(invalid(format!(
+                "async_buffer_size ({}) must be at least max_entry_size ({})",
+                self.async_buffer_size, self.max_entry_size,
+            )));
+        }
+        #[allow(unused_mut)]
+        let mut spec = self.spec;
+        #[cfg(target_os = "linux")]
+        if let Some(ref path) = self.spec_file {
+            // The logger isn't installed yet, so report errors on stderr.
+            match spec_file::read(path) {
+                Ok(s) => spec = Some(s),
+                Err(e) => {
+                    let _ = writeln!(std::io::stderr(), "{}; using the builder's spec", e);
+                }
+            }
+        }
+        let sinks = if self.sinks.is_empty() {
+            let mut sink = Sink::new(self.dest).format(self.fmt).color(self.color);
+            sink.formatter = self.formatter;
+            vec![sink]
         } else {
-            let fd = match self.dest {
-                Destination::Stderr => 2,
-                Destination::Stdout => 1,
-            };
-            unsafe { libc::isatty(fd) == 1 }
+            self.sinks
         };
 
-        Handle(Arc::new(Logger {
-            inner: Mutex::new(LoggerInner {
-                async_buf: Vec::with_capacity(ASYNC_BUF_SIZE),
-                use_async: false,
-            }),
+        let shards = async_buf::shard_count(self.async_buffer_size, self.max_entry_size);
+        let shard_size = self.async_buffer_size / shards;
+        let handle = Handle(Arc::new(Logger {
+            inner: Mutex::new(LoggerInner { reopen: false }),
             wake_consumer: Condvar::new(),
-            wake_producers: Condvar::new(),
-            spec: self.spec.unwrap_or_else(|| Specification::new("")),
-            fmt: self.fmt,
-            dest: self.dest,
-            use_color,
+            wake_flushers: Condvar::new(),
+            use_async: AtomicBool::new(false),
+            consumer_sleeping: AtomicBool::new(false),
+            shards: (0..shards).map(|_| Shard::new(shard_size)).collect(),
+            next_seq: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            spec: ArcSwap::from_pointee(
+                spec.unwrap_or_else(|| Specification::new(""))
+                    .or_legacy_prefix_matching(self.legacy_prefix_matching),
+            ),
+            installed: AtomicBool::new(false),
+            entries: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            overflow: self.overflow,
+            sinks: Sinks::open(
+                sinks,
+                self.legacy_prefix_matching,
+                self.max_entry_size,
+                FormatContext {
+                    use_color: false,
+                    location: self.location,
+                    file_basename: self.file_basename,
+                    thread: self.thread,
+                },
+            )?,
+            shard_size,
+            legacy_prefix_matching: self.legacy_prefix_matching,
+            async_mode: self.async_mode,
             is_test: self.is_test,
-        }))
+            #[cfg(test)]
+            stall: Mutex::new(()),
+        }));
+        #[cfg(target_os = "linux")]
+        if let Some(path) = self.spec_file {
+            spec_file::watch(handle.clone(), path.clone()).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("unable to watch spec file {}: {}", path.display(), e),
+                )
+            })?;
+        }
+        Ok(handle)
     }
 }
 
@@ -301,158 +831,952 @@ pub struct Handle(Arc<Logger>);
 impl Handle {
     /// Installs this logger as the global logger used by the `log` crate.
     /// Can only be called once in the lifetime of the program.
+    ///
+    /// If `Builder::async_mode` was set, this also enters asynchronous mode until `shutdown`.
     pub fn install(self) -> Result<(), log::SetLoggerError> {
         let logger = self.0;
 
         // Leak an instance of the Arc, so that the pointer lives forever.
         // This allows transmuting it to 'static soundly.
-        let l: &'static Logger = unsafe { &*Arc::into_raw(logger) };
-        log::set_logger(l)?;
-        log::set_max_level(l.spec.max);
+        let l: &'static Logger = unsafe { &*Arc::into_raw(logger.clone()) };
+        if let Err(e) = log::set_logger(l) {
+            // SAFETY: this is the instance leaked above; nothing else has seen the pointer.
+            drop(unsafe { Arc::from_raw(l) });
+            return Err(e);
+        }
+
+        {
+            // Hold the lock so a concurrent `set_spec` can't leave a stale max level.
+            let _l = l.inner.lock().unwrap();
+            l.installed.store(true, Ordering::Relaxed);
+            log::set_max_level(l.max_level());
+        }
+        let _ = GLOBAL.set(l);
+        if l.async_mode {
+            *GLOBAL_ASYNC.lock().unwrap() = Some(Handle(logger).async_guard());
+        }
         Ok(())
     }
 
-    /// Enables asynchronous logging until the returned `AsyncHandle` is dropped.
+    /// Replaces the logger-wide specification, e.g. to enable `debug` for one module without a
+    /// restart. Takes effect immediately for all threads; if this logger is installed, also
+    /// updates `log::set_max_level` accordingly.
+    ///
+    /// Sink specifications are unaffected.
+    pub fn set_spec(&self, spec: &str) {
+        self.set_specification(Specification::new(spec));
+    }
+
+    /// Replaces the logger-wide specification with an already-parsed one; see `set_spec`.
+    pub fn set_specification(&self, spec: Specification) {
+        let spec = spec.or_legacy_prefix_matching(self.0.legacy_prefix_matching);
+        let _l = self.0.inner.lock().unwrap();
+        self.0.spec.store(Arc::new(spec));
+        if self.0.installed.load(Ordering::Relaxed) {
+            log::set_max_level(self.0.max_level());
+        }
+    }
+
+    /// Enables asynchronous logging until the returned `AsyncGuard` is dropped.
     /// Typically this is called during `main` and held until shortly before returning to the OS.
-    /// During asynchronous mode, logging calls will not block for I/O until at least 1 MiB has
-    /// been buffered.
-    pub fn async_scope(&mut self) -> AsyncHandle<'_> {
-        let was_async = {
-            let mut l = self.0.inner.lock().unwrap();
-            std::mem::replace(&mut l.use_async, true)
-        };
+    /// During asynchronous mode, logging calls will not block for I/O until the buffer (see
+    /// `Builder::async_buffer_size`) is full.
+    ///
+    /// Panics if already in asynchronous mode.
+    pub fn async_guard(&self) -> AsyncGuard {
+        let (was_async, next_seq) = self.0.set_async(true);
         assert!(!was_async);
         let logger = self.0.clone();
-        AsyncHandle {
-            logger: self,
+        AsyncGuard {
+            logger: self.0.clone(),
             join: Some(
                 thread::Builder::new()
                     .name("logger".to_owned())
-                    .spawn(move || logger.run_async())
+                    .spawn(move || logger.run_async(next_seq))
                     .unwrap(),
             ),
         }
     }
+
+    /// Enables asynchronous logging until the returned `AsyncHandle` is dropped.
+    ///
+    /// This is as `async_guard`, except that the returned value borrows this handle.
+    pub fn async_scope(&mut self) -> AsyncHandle<'_> {
+        AsyncHandle {
+            _guard: self.async_guard(),
+            _handle: self,
+        }
+    }
+
+    /// Closes and reopens file destinations, so that after an external tool such as
+    /// `logrotate` renames the current file, subsequent entries go to a new file at the
+    /// original path. This avoids the races of `logrotate`'s `copytruncate` mode.
+    ///
+    /// While asynchronous, this happens on the logger thread between buffer swaps; entries
+    /// buffered before the swap are written to the new file. If reopening fails, the error is
+    /// reported on stderr and logging continues to the old file, so no entries are lost.
+    ///
+    /// This has no effect on other destinations.
+    pub fn reopen(&self) {
+        let mut l = self.0.inner.lock().unwrap();
+        if self.0.use_async.load(Ordering::Relaxed) {
+            l.reopen = true;
+            self.0.wake_consumer.notify_one();
+        } else {
+            self.0.sinks.reopen();
+        }
+    }
+
+    /// Installs a `SIGHUP` handler which calls `reopen`, as `logrotate`'s `postrotate` scripts
+    /// typically expect.
+    ///
+    /// The handler wakes a helper thread named `logger-sighup`. This replaces any previous
+    /// `SIGHUP` handler and can only be called once in the lifetime of the program.
+    ///
+    /// Available on Linux, Android, macOS, iOS, and FreeBSD.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    ))]
+    pub fn reopen_on_sighup(&self) -> Result<(), std::io::Error> {
+        sighup::install(self.clone())
+    }
+
+    /// Installs a panic hook which logs the panic's message, location, and a backtrace at
+    /// `error` level, after synchronously writing all buffered entries. Without this, entries
+    /// still buffered in asynchronous mode are lost if the panic ends the process, and they're
+    /// often exactly the context needed to debug it.
+    ///
+    /// The panic entry bypasses the logger-wide spec but not sink specs. This replaces any
+    /// previous panic hook, including the default one which prints to stderr.
+    ///
+    /// If the logger thread is in the middle of writing a batch, the hook waits up to half a
+    /// second for it to finish, so entries stay in order; past that, it proceeds without it.
+    pub fn log_panics(&self) {
+        crash::log_panics(self.clone())
+    }
+
+    /// Registers an `atexit` handler which synchronously writes all buffered entries, so that
+    /// `std::process::exit` during asynchronous mode doesn't lose them.
+    ///
+    /// As with `log_panics`, this waits up to half a second for any batch the logger thread is
+    /// in the middle of writing.
+    ///
+    /// Fails with `ErrorKind::AlreadyExists` if called more than once.
+    pub fn drain_at_exit(&self) -> Result<(), std::io::Error> {
+        crash::drain_at_exit(self.clone())
+    }
+
+    /// Listens on a Unix domain socket at `path` for commands to inspect or change the logger,
+    /// so operators can raise verbosity on a running process without a restart.
+    ///
+    /// The commands are `get-spec`, `set-spec SPEC`, `flush`, and `stats`, one per line; see
+    /// `examples/mylogctl.rs` for a client. A helper thread named `logger-control` accepts
+    /// clients, each served on a thread named `logger-control-conn`. Access control is up to the
+    /// socket's directory permissions.
+    pub fn listen_control<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        control::listen(self.clone(), path.as_ref())
+    }
 }
 
 pub struct AsyncHandle<'a> {
-    logger: &'a mut Handle,
+    _handle: &'a mut Handle,
+    _guard: AsyncGuard,
+}
+
+/// Keeps the logger in asynchronous mode; see `Handle::async_guard`.
+///
+/// On drop, leaves asynchronous mode, waiting for the logger thread to write all buffered
+/// entries.
+pub struct AsyncGuard {
+    logger: Arc<Logger>,
     join: Option<thread::JoinHandle<()>>,
 }
 
-impl<'a> Drop for AsyncHandle<'a> {
+impl Drop for AsyncGuard {
     fn drop(&mut self) {
-        let was_async = {
-            let mut l = self.logger.0.inner.lock().unwrap();
-            self.logger.0.wake_consumer.notify_one();
-            std::mem::replace(&mut l.use_async, false)
-        };
+        let (was_async, _) = self.logger.set_async(false);
         assert!(was_async);
         self.join.take().unwrap().join().unwrap();
     }
 }
 
+/// The guard for the global logger's asynchronous mode, as entered by `Handle::install` with
+/// `Builder::async_mode`.
+static GLOBAL_ASYNC: Mutex<Option<AsyncGuard>> = Mutex::new(None);
+
+/// The global logger, if it's this crate's, as installed by `Handle::install`.
+static GLOBAL: OnceLock<&'static Logger> = OnceLock::new();
+
+/// Leaves the global logger's asynchronous mode as entered by `Handle::install` with
+/// `Builder::async_mode`, waiting for all buffered entries to be written. Subsequent entries
+/// are written synchronously.
+///
+/// Typically this is called shortly before returning from `main`. It has no effect if the
+/// global logger isn't in asynchronous mode via `Builder::async_mode`.
+pub fn shutdown() {
+    let guard = GLOBAL_ASYNC.lock().unwrap().take();
+    drop(guard);
+}
+
 struct Logger {
     inner: Mutex<LoggerInner>,
+
+    /// Notified when there are entries for the logger thread, `reopen` is set, or asynchronous
+    /// mode ends. Used with `inner`.
     wake_consumer: Condvar,
-    wake_producers: Condvar,
-    fmt: Format,
-    spec: Specification,
-    dest: Destination,
-    use_color: bool,
+
+    /// Notified when `written` advances. Used with `inner`.
+    wake_flushers: Condvar,
+
+    /// True iff in asynchronous mode. Changed only while holding `inner` and all shard locks,
+    /// so it can be read while holding either.
+    use_async: AtomicBool,
+
+    /// True iff the logger thread may be waiting on `wake_consumer`, so producers know to
+    /// notify it. Producers set their entry's sequence number before reading this, and the
+    /// logger thread sets this before reading `next_seq`, so one or the other always notices.
+    consumer_sleeping: AtomicBool,
+
+    shards: Box<[Shard]>,
+
+    /// The sequence number of the next entry, assigned while holding its shard's lock.
+    next_seq: AtomicU64,
+
+    /// All entries with lesser sequence numbers have been written. Changed only while holding
+    /// `inner`, so waiters on `wake_flushers` don't miss updates, but readable without it.
+    written: AtomicU64,
+
+    spec: ArcSwap<Specification>,
+
+    /// True iff this is the global logger, and thus should update `log::set_max_level`.
+    installed: AtomicBool,
+
+    /// The number of entries accepted by `log`.
+    entries: AtomicU64,
+
+    /// The number of entries dropped due to `overflow`.
+    dropped: AtomicU64,
+
+    overflow: Overflow,
+
+    /// The capacity of each shard. It's at least the maximum entry size, so `Overflow::Block`
+    /// can't block forever waiting for space.
+    shard_size: usize,
+
+    sinks: Sinks,
+    legacy_prefix_matching: bool,
+
+    /// True iff `Handle::install` should enter asynchronous mode.
+    async_mode: bool,
+
     is_test: bool,
+
+    /// Held by tests to stall the logger thread between taking a batch and writing it.
+    #[cfg(test)]
+    stall: Mutex<()>,
+}
+
+/// How long `Logger::drain` waits for the logger thread to write the entries it has taken.
+///
+/// `drain` runs only as the process is dying (from a panic hook, `atexit` handler, or `fatal!`),
+/// so this is short: a stuck destination shouldn't noticeably delay the exit. If it expires,
+/// those entries may be written after the rest or not at all.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+thread_local! {
+    /// True iff this is the asynchronous logger thread.
+    static ON_LOGGER_THREAD: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
 }
 
+/// Lock order: `Logger::inner` (if needed), then shards in index order.
 struct LoggerInner {
-    async_buf: Vec<u8>,
-    use_async: bool,
+    /// True iff `Handle::reopen` has been called but the logger thread hasn't acted on it yet.
+    reopen: bool,
 }
 
 impl Logger {
-    /// Writes from `buf` to the target (stdout or stderr).
+    /// Returns the most detailed level enabled by both the logger's spec and some sink.
+    fn max_level(&self) -> log::LevelFilter {
+        std::cmp::min(self.spec.load().max, self.sinks.max_level())
+    }
+
+    /// Returns counters as `key=value` pairs, for the control socket's `stats` command.
+    fn stats(&self) -> String {
+        let buffered_bytes: usize = self
+            .shards
+            .iter()
+            .map(|s| s.inner.lock().unwrap().buf.bytes.len())
+            .sum();
+        format!(
+            "entries={} dropped={} async={} buffered_bytes={}",
+            self.entries.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.use_async.load(Ordering::Relaxed),
+            buffered_bytes,
+        )
+    }
+
+    /// Enters or leaves asynchronous mode, waking the logger thread. Returns the previous mode
+    /// and the next sequence number.
+    fn set_async(&self, use_async: bool) -> (bool, u64) {
+        let _l = self.inner.lock().unwrap();
+        let _shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|s| s.inner.lock().unwrap())
+            .collect();
+        let was_async = self.use_async.swap(use_async, Ordering::Relaxed);
+        self.wake_consumer.notify_one();
+        (was_async, self.next_seq.load(Ordering::Relaxed))
+    }
+
+    /// Returns another shard with room for an entry of `len` bytes if `shard` (locked via `l`)
+    /// is full, or else `shard` itself.
+    ///
+    /// This uses `try_lock`, as `shard`'s lock is held and may not be in lock order; a shard
+    /// which is busy is likely being drained anyway.
+    fn spill<'a>(
+        &'a self,
+        shard: &'a Shard,
+        l: MutexGuard<'a, ShardInner>,
+        len: usize,
+    ) -> (&'a Shard, MutexGuard<'a, ShardInner>) {
+        let full = |l: &ShardInner| l.buf.bytes.len() + len > self.shard_size;
+        if !full(&l) {
+            return (shard, l);
+        }
+        for other in self.shards.iter() {
+            if std::ptr::eq(other, shard) {
+                continue;
+            }
+            if let Ok(other_l) = other.inner.try_lock() {
+                if !full(&other_l) {
+                    return (other, other_l);
+                }
+            }
+        }
+        (shard, l)
+    }
+
+    /// Makes room in `shard` for an entry of `len` bytes, as dictated by `overflow`.
+    /// Returns the guard and true if there's room; false if the entry should be dropped.
+    fn make_room<'a>(
+        &self,
+        shard: &Shard,
+        mut l: MutexGuard<'a, ShardInner>,
+        len: usize,
+        level: Level,
+    ) -> (MutexGuard<'a, ShardInner>, bool) {
+        let full = |l: &ShardInner| l.buf.bytes.len() + len > self.shard_size;
+        match self.overflow {
+            // Theoretically a large entry could be starved by shorter entries, but it seems
+            // unlikely to be problematic.
+            Overflow::Block => {
+                while full(&l) {
+                    l = shard.wake_producers.wait(l).unwrap();
+                }
+            }
+            Overflow::DropNewest => {}
+            Overflow::DropLowestSeverity => {
+                if full(&l) {
+                    let evicted = l
+                        .buf
+                        .evict_less_severe(level, self.shard_size.saturating_sub(len));
+                    self.count_dropped(&mut l, evicted as u64);
+                }
+            }
+            Overflow::BlockTimeout(timeout) => {
+                let deadline = Instant::now() + timeout;
+                while full(&l) {
+                    let remaining = match deadline.checked_duration_since(Instant::now()) {
+                        Some(r) if r > Duration::ZERO => r,
+                        _ => break,
+                    };
+                    l = shard.wake_producers.wait_timeout(l, remaining).unwrap().0;
+                }
+            }
+        }
+        if full(&l) {
+            self.count_dropped(&mut l, 1);
+            return (l, false);
+        }
+        (l, true)
+    }
+
+    fn count_dropped(&self, l: &mut ShardInner, n: u64) {
+        l.dropped += n;
+        self.dropped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Synchronously writes all buffered entries from the calling thread, then `record` (if any)
+    /// to each sink whose spec enables it, bypassing the logger-wide spec. This is for when the
+    /// process may die before the logger thread writes them.
     ///
-    /// When operating asynchronously, called only from `run_async`.
-    /// When operating synchronously, called directly from `log`.
-    fn write_all(&self, buf: &[u8]) -> Result<(), std::io::Error> {
-        match self.dest {
-            Destination::Stderr => std::io::stderr().write_all(buf),
-            Destination::Stdout => std::io::stdout().write_all(buf),
+    /// Entries already taken by the logger thread are left to it, but this waits (up to
+    /// `DRAIN_TIMEOUT`) for it to write them first, so entries aren't reordered. Then this holds
+    /// all shard locks throughout, so entries logged concurrently are written afterward.
+    fn drain(&self, record: Option<&Record>) {
+        // The process may be dying due to a panic elsewhere; write whatever's there.
+        let lock_shards = || -> Vec<_> {
+            self.shards
+                .iter()
+                .map(|s| s.inner.lock().unwrap_or_else(PoisonError::into_inner))
+                .collect()
+        };
+        let mut shards = lock_shards();
+
+        // Entries with sequence numbers before the first remaining in the shards have been
+        // taken by the logger thread. Wait for it, unless this is the logger thread itself (as
+        // when a sink panics) or it seems stuck.
+        if !ON_LOGGER_THREAD.with(|t| t.get()) {
+            let deadline = Instant::now() + DRAIN_TIMEOUT;
+            loop {
+                let taken = shards
+                    .iter()
+                    .filter_map(|l| l.buf.seqs.first().copied())
+                    .min()
+                    .unwrap_or_else(|| self.next_seq.load(Ordering::Relaxed));
+                if self.written.load(Ordering::Acquire) >= taken {
+                    break;
+                }
+
+                // Follow lock order: release the shards before locking `inner`.
+                drop(shards);
+                let mut l = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+                while self.written.load(Ordering::Acquire) < taken {
+                    let remaining = match deadline.checked_duration_since(Instant::now()) {
+                        Some(r) if r > Duration::ZERO => r,
+                        _ => break,
+                    };
+                    l = self
+                        .wake_flushers
+                        .wait_timeout(l, remaining)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                drop(l);
+                shards = lock_shards();
+                if Instant::now() >= deadline {
+                    break;
+                }
+            }
+        }
+        let mut bufs = Vec::with_capacity(shards.len());
+        let mut dropped = 0;
+        for l in shards.iter_mut() {
+            bufs.push(std::mem::replace(&mut l.buf, AsyncBuf::with_capacity(0)));
+            dropped += std::mem::replace(&mut l.dropped, 0);
+        }
+        let mut merged = AsyncBuf::with_capacity(0);
+        merged.merge_from(&bufs);
+        if !merged.is_empty() {
+            self.sinks
+                .write_batch(&merged, &mut AsyncBuf::with_capacity(0));
+        }
+        if dropped > 0 {
+            self.write_dropped(dropped);
+        }
+        if let Some(record) = record {
+            self.entries.fetch_add(1, Ordering::Relaxed);
+            let mask = self.sinks.enabled(record.metadata());
+            self.sinks.format(record, mask, |buf, mask| {
+                self.sinks.write_entry(buf, mask, self.is_test)
+            });
+        }
+
+        // Return the (now empty) buffers to keep their capacity.
+        for (l, mut buf) in shards.iter_mut().zip(bufs) {
+            buf.clear();
+            l.buf = buf;
         }
     }
 
-    fn run_async(&self) {
-        let mut buf = Vec::with_capacity(ASYNC_BUF_SIZE);
+    /// Writes a synthetic entry to all sinks reporting that `n` entries were dropped.
+    fn write_dropped(&self, n: u64) {
+        self.sinks.format(
+            &Record::builder()
+                .args(format_args!("{} log entries dropped", n))
+                .level(Level::Warn)
+                .target(module_path!())
+                .module_path_static(Some(module_path!()))
+                .file_static(Some(file!()))
+                .line(Some(line!()))
+                .build(),
+            self.sinks.all(),
+            |buf, mask| self.sinks.write_entry(buf, mask, self.is_test),
+        );
+    }
+
+    /// Runs the logger thread, given the sequence number of the first entry it's to write.
+    fn run_async(&self, mut taken: u64) {
+        ON_LOGGER_THREAD.with(|t| t.set(true));
+        let mut bufs: Vec<AsyncBuf> = (0..self.shards.len())
+            .map(|_| AsyncBuf::with_capacity(self.shard_size))
+            .collect();
+        let mut merged = AsyncBuf::with_capacity(match self.shards.len() {
+            1 => 0,
+            n => n * self.shard_size,
+        });
+        let mut scratch = AsyncBuf::with_capacity(0);
         let mut use_async = true;
         while use_async {
-            // Swap logger's async_buf (which has bytes to write) with an empty buf.
-            {
+            let reopen = {
                 let mut l = self.inner.lock().unwrap();
-                if l.async_buf.is_empty() && l.use_async {
+                while self.use_async.load(Ordering::Relaxed) && !l.reopen {
+                    self.consumer_sleeping.store(true, Ordering::SeqCst);
+                    if self.next_seq.load(Ordering::SeqCst) != taken {
+                        break;
+                    }
                     l = self.wake_consumer.wait(l).unwrap();
                 }
-                use_async = l.use_async;
-                buf.clear();
-                std::mem::swap(&mut buf, &mut l.async_buf);
-                self.wake_producers.notify_all();
+                self.consumer_sleeping.store(false, Ordering::Relaxed);
+                use_async = self.use_async.load(Ordering::Relaxed);
+                std::mem::replace(&mut l.reopen, false)
             };
 
-            // Write buf.
-            if !buf.is_empty() {
-                // This can throw an error, but what are we going to do, log it? Discard.
-                let _ = self.write_all(&buf);
+            // Swap each shard (which may have bytes to write) with an empty buf. Hold all the
+            // locks at once, so the batch has exactly the entries before `taken`.
+            let mut dropped = 0;
+            {
+                let mut shards: Vec<_> = self
+                    .shards
+                    .iter()
+                    .map(|s| s.inner.lock().unwrap())
+                    .collect();
+                taken = self.next_seq.load(Ordering::Relaxed);
+                for (l, buf) in shards.iter_mut().zip(bufs.iter_mut()) {
+                    buf.clear();
+                    std::mem::swap(buf, &mut l.buf);
+                    dropped += std::mem::replace(&mut l.dropped, 0);
+                }
+            }
+            for s in self.shards.iter() {
+                s.wake_producers.notify_all();
             }
+
+            if reopen {
+                self.sinks.reopen();
+            }
+
+            #[cfg(test)]
+            drop(self.stall.lock().unwrap());
+
+            // Write the entries in the order they were logged.
+            let batch = if bufs.len() == 1 {
+                &bufs[0]
+            } else {
+                merged.clear();
+                merged.merge_from(&bufs);
+                &merged
+            };
+            if !batch.is_empty() {
+                self.sinks.write_batch(batch, &mut scratch);
+            }
+
+            // Now that there's room again, report any entries dropped before the swap.
+            if dropped > 0 {
+                self.write_dropped(dropped);
+            }
+
+            {
+                let _l = self.inner.lock().unwrap();
+                self.written.store(taken, Ordering::Release);
+            }
+            self.wake_flushers.notify_all();
         }
     }
 }
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.spec.get_level(metadata.target()) >= metadata.level()
+        self.spec.load().get_level(metadata.target()) >= metadata.level()
     }
 
     fn log(&self, record: &Record) {
-        if !self.enabled(record.metadata()) {
+        // Check in two phases: first the level, then the (potentially more expensive) message
+        // filter, all before formatting any entries.
+        let spec = self.spec.load();
+        if spec.get_level(record.metadata().target()) < record.metadata().level() {
+            return;
+        }
+        let mask = self.sinks.enabled(record.metadata());
+        if mask == 0 || !spec.is_match(record.args()) {
+            return;
+        }
+        let mask = self.sinks.filter(record, mask);
+        if mask == 0 {
             return;
         }
+        self.entries.fetch_add(1, Ordering::Relaxed);
 
-        // Always write into an EntryBuf first. This minimizes thread contention, whether async is
-        // enabled or not.
-        let mut buf = EntryBuf::new();
+        self.sinks.format(record, mask, |buf, mask| {
+            let shard = &self.shards[async_buf::shard_index(self.shards.len())];
+            let l = shard.inner.lock().unwrap();
+            if self.is_test || !self.use_async.load(Ordering::Relaxed) {
+                drop(l);
+                let _l = self.inner.lock().unwrap();
+                self.sinks.write_entry(buf, mask, self.is_test);
+                return;
+            }
 
-        // Write as much as fits; ignore truncation, which is the only possible error.
-        let _ = self.fmt.write(self.use_color, record, &mut buf);
-        let buf = buf.terminate();
-        let buf = buf.get();
+            // Make room in the shard, then copy and notify the logger thread if it's waiting.
+            let (shard, l) = self.spill(shard, l, buf.len());
+            let (mut l, room) = self.make_room(shard, l, buf.len(), record.level());
+            if !room {
+                return;
+            }
+            let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+            l.buf.push(buf, mask, record.level(), seq);
+            drop(l);
+            if self.consumer_sleeping.load(Ordering::SeqCst) {
+                let _l = self.inner.lock().unwrap();
+                self.wake_consumer.notify_one();
+            }
+        });
+    }
 
+    fn flush(&self) {
         let mut l = self.inner.lock().unwrap();
+        if self.use_async.load(Ordering::Relaxed) {
+            let target = self.next_seq.load(Ordering::SeqCst);
+            while self.written.load(Ordering::Acquire) < target {
+                l = self.wake_flushers.wait(l).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Builder, Destination, Format, FormatContext, Location, LogFile, Overflow, ThreadColumn,
+    };
+    use crate::testutil::{self, tempdir};
+    use log::{Level, Log as _, Record};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
 
-        if self.is_test {
-            match self.dest {
-                Destination::Stderr => eprint!("{}", buf),
-                Destination::Stdout => print!("{}", buf),
+    #[test]
+    fn set_spec() {
+        let h = Builder::new().spec("info").is_test(true).build();
+        let debug = log::Metadata::builder()
+            .level(log::Level::Debug)
+            .target("foo::bar")
+            .build();
+        assert!(!h.0.enabled(&debug));
+        h.set_spec("info,foo=debug");
+        assert!(h.0.enabled(&debug));
+        assert_eq!(h.0.max_level(), log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn google_location() {
+        let record = Record::builder()
+            .args(format_args!("hi"))
+            .level(Level::Info)
+            .target("foo::bar")
+            .file(Some("src/bar.rs"))
+            .line(Some(42))
+            .build();
+        let format = |fmt: Format, location, file_basename| {
+            let ctx = FormatContext {
+                location,
+                file_basename,
+                ..Default::default()
+            };
+            testutil::format(crate::MAX_ENTRY_SIZE, |buf| fmt.write(&ctx, &record, buf))
+        };
+        let google = |location, file_basename| format(Format::Google, location, file_basename);
+        assert!(google(Location::Target, true).ends_with(" foo::bar] hi\n"));
+        assert!(google(Location::FileLine, false).ends_with(" src/bar.rs:42] hi\n"));
+        assert!(google(Location::FileLine, true).ends_with(" bar.rs:42] hi\n"));
+        assert!(google(Location::Both, true).ends_with(" foo::bar bar.rs:42] hi\n"));
+        let systemd = format(Format::GoogleSystemd, Location::Both, false);
+        assert!(systemd.starts_with("<5>"), "{}", systemd);
+        assert!(
+            systemd.ends_with(" foo::bar src/bar.rs:42] hi\n"),
+            "{}",
+            systemd
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn google_thread() {
+        let format = |thread| {
+            let record = Record::builder()
+                .args(format_args!("hi"))
+                .level(Level::Info)
+                .target("foo")
+                .build();
+            let ctx = FormatContext {
+                thread,
+                ..Default::default()
+            };
+            testutil::format(crate::MAX_ENTRY_SIZE, |buf| {
+                Format::GoogleSystemd.write(&ctx, &record, buf)
+            })
+        };
+        let run = |name: Option<&str>| {
+            let mut b = std::thread::Builder::new();
+            if let Some(name) = name {
+                b = b.name(name.to_owned());
             }
-            return;
-        } else if !l.use_async {
-            let _ = self.write_all(buf.as_bytes());
-            return;
+            b.spawn(move || {
+                // SAFETY: `gettid` has no preconditions.
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+                let formatted = [
+                    format(ThreadColumn::Name),
+                    format(ThreadColumn::Tid),
+                    format(ThreadColumn::NameAndTid),
+                ];
+                (tid, formatted)
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+        };
+        let (tid, [name, only_tid, both]) = run(Some("worker"));
+        assert_eq!(name, "<5>worker foo] hi\n");
+        assert_eq!(only_tid, format!("<5>{} foo] hi\n", tid));
+        assert_eq!(both, format!("<5>worker:{} foo] hi\n", tid));
+        let (tid, [name, only_tid, both]) = run(None);
+        assert!(name.starts_with("<5>ThreadId("), "{}", name);
+        assert_eq!(only_tid, format!("<5>{} foo] hi\n", tid));
+        assert_eq!(both, only_tid);
+    }
+
+    /// Tests dropping entries when the async buffer is full, then reporting the drops.
+    #[test]
+    fn overflow_drop_newest() {
+        let dir = tempdir("overflow");
+        let path = dir.join("log");
+        let mut h = Builder::new()
+            .spec("info")
+            .destination(Destination::File(LogFile::new(&path)))
+            .overflow(Overflow::DropNewest)
+            .build();
+
+        // Use 4 shards regardless of the CPU count, each with room for 4 entries, as each entry
+        // is truncated to `MAX_ENTRY_SIZE`.
+        let per_shard = 4;
+        let l = std::sync::Arc::get_mut(&mut h.0).unwrap();
+        l.shard_size = per_shard * crate::MAX_ENTRY_SIZE;
+        l.shards = (0..4).map(|_| super::Shard::new(l.shard_size)).collect();
+
+        // Fill the buffer with no logger thread running. Once this thread's shard is full, its
+        // entries spill into the others.
+        let fit = 4 * per_shard;
+        h.0.use_async.store(true, Ordering::Relaxed);
+        let msg = "x".repeat(100_000);
+        for i in 0..20 {
+            h.0.log(
+                &Record::builder()
+                    .args(format_args!("{:02} {}", i, msg))
+                    .level(Level::Info)
+                    .target("foo")
+                    .build(),
+            );
         }
+        let dropped = h.0.dropped.load(Ordering::Relaxed);
+        assert_eq!(dropped, 20 - fit as u64);
 
-        // Wait for there to be room in the buffer, then copy and notify the logger thread.
-        // Theoretically a large entry could be starved by shorter entries, but it seems unlikely
-        // to be problematic.
-        while l.async_buf.len() + buf.len() > ASYNC_BUF_SIZE {
-            l = self.wake_producers.wait(l).unwrap();
+        // Run the logger thread's loop once.
+        h.0.use_async.store(false, Ordering::Relaxed);
+        h.0.run_async(0);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), fit + 1);
+        for (i, line) in lines[..fit].iter().enumerate() {
+            assert!(line.contains(&format!("] {:02} x", i)), "{}", &line[..50]);
         }
-        l.async_buf.extend_from_slice(buf.as_bytes());
-        self.wake_consumer.notify_one();
+        assert!(
+            lines[fit].ends_with(&format!("] {} log entries dropped", dropped)),
+            "{}",
+            lines[fit]
+        );
+        assert_eq!(h.0.written.load(Ordering::Relaxed), fit as u64);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn flush(&self) {
-        let mut l = self.inner.lock().unwrap();
-        if l.use_async {
-            while !l.async_buf.is_empty() {
-                l = self.wake_producers.wait(l).unwrap();
+    /// Tests synchronously writing buffered entries, as on panic or exit.
+    #[test]
+    fn drain() {
+        let dir = tempdir("drain");
+        let path = dir.join("log");
+        let h = Builder::new()
+            .spec("warn")
+            .destination(Destination::File(LogFile::new(&path)))
+            .build();
+
+        // Buffer entries with no logger thread running.
+        h.0.use_async.store(true, Ordering::Relaxed);
+        for i in 0..3 {
+            h.0.log(
+                &Record::builder()
+                    .args(format_args!("buffered {}", i))
+                    .level(Level::Warn)
+                    .target("foo")
+                    .build(),
+            );
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        // The final entry bypasses the logger-wide spec.
+        h.0.drain(Some(
+            &Record::builder()
+                .args(format_args!("final"))
+                .level(Level::Info)
+                .target("foo")
+                .build(),
+        ));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let msgs: Vec<&str> = contents
+            .lines()
+            .map(|l| &l[l.find("] ").unwrap() + 2..])
+            .collect();
+        assert_eq!(msgs, ["buffered 0", "buffered 1", "buffered 2", "final"]);
+        assert!(h
+            .0
+            .shards
+            .iter()
+            .all(|s| s.inner.lock().unwrap().buf.is_empty()));
+        h.0.use_async.store(false, Ordering::Relaxed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that draining waits for the batch the logger thread is in the middle of writing,
+    /// so nothing is lost or reordered.
+    #[test]
+    fn drain_in_flight() {
+        let dir = tempdir("drain-in-flight");
+        let path = dir.join("log");
+        let h = Builder::new()
+            .spec("info")
+            .destination(Destination::File(LogFile::new(&path)))
+            .build();
+        let _a = h.async_guard();
+        let log = |msg: &str| {
+            h.0.log(
+                &Record::builder()
+                    .args(format_args!("{}", msg))
+                    .level(Level::Info)
+                    .target("foo")
+                    .build(),
+            )
+        };
+
+        // Stall the logger thread after it takes the first entry.
+        let stall = h.0.stall.lock().unwrap();
+        log("taken");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while h
+            .0
+            .shards
+            .iter()
+            .any(|s| !s.inner.lock().unwrap().buf.is_empty())
+        {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        log("buffered");
+        std::thread::scope(|s| {
+            let drainer = s.spawn(|| {
+                h.0.drain(Some(
+                    &Record::builder()
+                        .args(format_args!("final"))
+                        .level(Level::Info)
+                        .target("foo")
+                        .build(),
+                ))
+            });
+            std::thread::sleep(Duration::from_millis(100));
+            drop(stall);
+            drainer.join().unwrap();
+        });
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let msgs: Vec<&str> = contents
+            .lines()
+            .map(|l| &l[l.find("] ").unwrap() + 2..])
+            .collect();
+        assert_eq!(msgs, ["taken", "buffered", "final"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that concurrent threads' entries are written whole, each thread's in order, and
+    /// that `flush` waits for all of them.
+    #[test]
+    fn async_threads() {
+        let dir = tempdir("async");
+        let path = dir.join("log");
+        let mut h = Builder::new()
+            .spec("info")
+            .destination(Destination::File(LogFile::new(&path)))
+            .max_entry_size(1024)
+            .async_buffer_size(16 * 1024)
+            .build();
+
+        // Use 4 shards regardless of the CPU count, to exercise merging.
+        let l = std::sync::Arc::get_mut(&mut h.0).unwrap();
+        l.shard_size = 4096;
+        l.shards = (0..4).map(|_| super::Shard::new(4096)).collect();
+
+        let _a = h.async_guard();
+        let logger = &*h.0;
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        logger.log(
+                            &Record::builder()
+                                .args(format_args!("thread={} i={} {}", t, i, "x".repeat(i % 100)))
+                                .level(Level::Info)
+                                .target("foo")
+                                .build(),
+                        );
+                    }
+                });
             }
+        });
+        logger.flush();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let mut next = [0; 4];
+        for line in contents.lines() {
+            let rest = &line[line.find("] thread=").unwrap() + 9..];
+            let mut parts = rest.split(' ');
+            let t: usize = parts.next().unwrap().parse().unwrap();
+            let i: usize = parts
+                .next()
+                .unwrap()
+                .strip_prefix("i=")
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(i, next[t], "{}", line);
+            assert_eq!(parts.next().unwrap(), "x".repeat(i % 100), "{}", line);
+            next[t] += 1;
         }
+        assert_eq!(next, [1000; 4]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "async_buffer_size (1024) must be at least max_entry_size (4096)")]
+    fn async_buffer_smaller_than_entry() {
+        Builder::new()
+            .max_entry_size(4096)
+            .async_buffer_size(1024)
+            .build();
+    }
+
+    #[test]
+    fn try_build_invalid() {
+        let e = Builder::new().max_entry_size(0).try_build().err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "max_entry_size must be at least 1");
     }
 }
@@ -0,0 +1,306 @@
+//! The [logfmt](https://brandur.org/logfmt) format: space-separated `key=value` pairs.
+
+use crate::entry_buf::{EntryBuf, Writing};
+use crate::json::{level_str, Escaper};
+use log::Record;
+use std::fmt::{Display, Write as _};
+use std::thread;
+
+/// Appended after a truncated message to close the quoted value.
+///
+/// Space for this is reserved throughout the entry, so it always fits.
+const TRUNCATED_IN_STRING: &str = "\" truncated=true";
+
+/// Writes `record` as a logfmt line.
+///
+/// Typical entry:
+/// ```text
+/// ts=2021-03-08T21:31:24.255123-08:00 level=info thread=main target=moonfire_nvr msg="Success."
+/// ```
+///
+/// Key-values attached to the record appear as additional pairs before `msg`, with any spaces,
+/// quotes, `=`, `\`, or control characters in their keys replaced by `_`.
+/// The message is always quoted. Other values are quoted and escaped only if they are empty or
+/// contain spaces, quotes, `=`, `\`, or control characters.
+/// If the entry would exceed the maximum entry size, pairs which don't fit are dropped, the
+/// message is shortened as necessary, and a `truncated=true` pair is added.
+pub(crate) fn write(record: &Record, buf: &mut EntryBuf<Writing>) -> Result<(), std::fmt::Error> {
+    buf.set_reserved(TRUNCATED_IN_STRING.len());
+    let mut line = Line {
+        buf,
+        first: true,
+        in_string: false,
+        truncated: false,
+    };
+    let result = write_pairs(record, &mut line);
+    let (in_string, first, truncated) = (line.in_string, line.first, line.truncated);
+    buf.set_reserved(0);
+    match result {
+        Ok(()) if !truncated => Ok(()),
+        _ if in_string => buf.write_str(TRUNCATED_IN_STRING),
+        _ if first => buf.write_str("truncated=true"),
+        _ => buf.write_str(" truncated=true"),
+    }
+}
+
+fn write_pairs(record: &Record, line: &mut Line) -> Result<(), std::fmt::Error> {
+    const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
+    line.pair("ts", jiff::Zoned::now().strftime(TIME_FORMAT))?;
+    line.pair("level", level_str(record.level()))?;
+    let t = thread::current();
+    match t.name() {
+        Some(name) => line.pair("thread", name)?,
+        None => line.pair("thread", format_args!("{:?}", t.id()))?,
+    }
+    line.pair("target", record.target())?;
+    crate::kv::for_each(record, |k, v| line.pair(k.as_str(), v))?;
+    line.message_pair("msg", record.args())
+}
+
+/// A logfmt line being written to an `EntryBuf`.
+///
+/// Each pair is written entirely or not at all; a pair which doesn't fit is omitted (setting
+/// `truncated`) so that later pairs, notably the message, still have a chance. The final
+/// message pair may instead be cut off (at an escape sequence boundary) with `in_string` set.
+struct Line<'a, 'b> {
+    buf: &'a mut EntryBuf<'b, Writing>,
+
+    /// True iff no pairs have been written yet.
+    first: bool,
+
+    /// True iff truncation happened within a quoted value which needs to be closed.
+    in_string: bool,
+
+    /// True iff a pair was omitted because it didn't fit.
+    truncated: bool,
+}
+
+impl Line<'_, '_> {
+    /// Writes the (space-separated) key and `=`.
+    fn key(&mut self, key: &str) -> Result<(), std::fmt::Error> {
+        if !self.first {
+            self.buf.write_str(" ")?;
+        }
+        write_key(self.buf, key)?;
+        self.buf.write_str("=")
+    }
+
+    /// Writes a pair, quoting the value only if necessary, or omits it if it doesn't fit.
+    fn pair(&mut self, key: &str, value: impl Display) -> Result<(), std::fmt::Error> {
+        let mark = self.buf.len();
+        let result = self.key(key).and_then(|()| write_value(self.buf, value));
+        if result.is_err() {
+            self.buf.rollback(mark);
+            self.truncated = true;
+            return Ok(());
+        }
+        self.first = false;
+        Ok(())
+    }
+
+    /// Writes a pair with a quoted value which may be cut off rather than omitted on truncation.
+    fn message_pair(&mut self, key: &str, value: impl Display) -> Result<(), std::fmt::Error> {
+        let mark = self.buf.len();
+        if let Err(e) = self.key(key).and_then(|()| self.buf.write_str("\"")) {
+            self.buf.rollback(mark);
+            return Err(e);
+        }
+        self.first = false;
+        self.in_string = true;
+        write!(Escaper(self.buf), "{}", value)?;
+        self.buf.write_str("\"")?;
+        self.in_string = false;
+        Ok(())
+    }
+}
+
+/// Writes a key, replacing anything which would make the pair ambiguous (spaces, quotes, `=`,
+/// `\`, or control characters) with `_`. An empty key is written as `_`.
+pub(crate) fn write_key(buf: &mut EntryBuf<Writing>, key: &str) -> Result<(), std::fmt::Error> {
+    if key.is_empty() {
+        return buf.write_str("_");
+    }
+    let mut rest = key;
+    while let Some(i) = rest.find(|c: char| c <= ' ' || matches!(c, '"' | '=' | '\\' | '\x7f')) {
+        buf.write_str(&rest[..i])?;
+        buf.write_str("_")?;
+        rest = &rest[i + 1..];
+    }
+    buf.write_str(rest)
+}
+
+/// Writes a value, quoting and escaping it only if necessary.
+pub(crate) fn write_value(
+    buf: &mut EntryBuf<Writing>,
+    value: impl Display,
+) -> Result<(), std::fmt::Error> {
+    let value_start = buf.len();
+    let mut plain = Plain {
+        buf,
+        needs_quotes: false,
+    };
+    write!(plain, "{}", value)?;
+    if plain.needs_quotes || buf.len() == value_start {
+        // Start over with a quoted value.
+        buf.rollback(value_start);
+        buf.write_str("\"")?;
+        write!(Escaper(buf), "{}", value)?;
+        buf.write_str("\"")?;
+    }
+    Ok(())
+}
+
+/// Writes a value verbatim, noting if it contains anything which requires quoting.
+struct Plain<'a, 'b> {
+    buf: &'a mut EntryBuf<'b, Writing>,
+    needs_quotes: bool,
+}
+
+impl std::fmt::Write for Plain<'_, '_> {
+    fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
+        if s.bytes()
+            .any(|b| b <= b' ' || b == b'"' || b == b'=' || b == b'\\')
+        {
+            self.needs_quotes = true;
+            return Ok(()); // the caller will start over.
+        }
+        if self.needs_quotes {
+            return Ok(());
+        }
+        self.buf.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testutil, MAX_ENTRY_SIZE};
+
+    fn format(record: &log::Record) -> String {
+        testutil::format(MAX_ENTRY_SIZE, |buf| super::write(record, buf))
+    }
+
+    /// Returns the entry with the variable `ts` and `thread` pairs removed.
+    fn strip_variable(entry: &str) -> String {
+        let start = entry.find("ts=").unwrap();
+        let level = entry.find(" level=").unwrap();
+        let thread = entry.find(" thread=").unwrap();
+        let target = entry.find(" target=").unwrap();
+        format!(
+            "{}{}{}",
+            &entry[..start],
+            &entry[level + 1..thread],
+            &entry[target..]
+        )
+    }
+
+    #[test]
+    fn quoting() {
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("say \"hi\"\nnow"))
+                .level(log::Level::Info)
+                .target("a b=c")
+                .build(),
+        );
+        assert_eq!(
+            strip_variable(&entry),
+            "level=info target=\"a b=c\" msg=\"say \\\"hi\\\"\\nnow\"\n"
+        );
+    }
+
+    #[test]
+    fn unquoted() {
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("Success."))
+                .level(log::Level::Warn)
+                .target("moonfire_nvr")
+                .build(),
+        );
+        assert_eq!(
+            strip_variable(&entry),
+            "level=warn target=moonfire_nvr msg=\"Success.\"\n"
+        );
+    }
+
+    #[test]
+    fn key_values() {
+        let kvs = [("camera_id", 3), ("stream", 1)];
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("hi"))
+                .level(log::Level::Info)
+                .target("foo")
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(
+            strip_variable(&entry),
+            "level=info target=foo camera_id=3 stream=1 msg=\"hi\"\n"
+        );
+    }
+
+    #[test]
+    fn hostile_keys() {
+        let kvs = [("a b=\"c\"\nd", 1), ("", 2), ("x\\y", 3)];
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("hi"))
+                .level(log::Level::Info)
+                .target("foo")
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(
+            strip_variable(&entry),
+            "level=info target=foo a_b__c__d=1 _=2 x_y=3 msg=\"hi\"\n"
+        );
+    }
+
+    #[test]
+    fn truncated_message() {
+        let msg = "m".repeat(MAX_ENTRY_SIZE);
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("{}", msg))
+                .level(log::Level::Info)
+                .target("foo")
+                .build(),
+        );
+        assert!(entry.len() <= MAX_ENTRY_SIZE);
+        assert!(entry.ends_with("m\" truncated=true\n"), "{}", entry);
+    }
+
+    #[test]
+    fn truncated_pair() {
+        let big = "x".repeat(500);
+        let kvs = [("big", &big[..])];
+        let record = log::Record::builder()
+            .args(format_args!("disk full"))
+            .level(log::Level::Warn)
+            .target("probe")
+            .key_values(&kvs)
+            .build();
+        let entry = testutil::format(200, |buf| super::write(&record, buf));
+        assert!(entry.len() <= 200);
+        assert_eq!(
+            strip_variable(&entry),
+            "level=warn target=probe msg=\"disk full\" truncated=true\n"
+        );
+
+        let target = "t".repeat(MAX_ENTRY_SIZE);
+        let entry = format(
+            &log::Record::builder()
+                .args(format_args!("msg"))
+                .level(log::Level::Warn)
+                .target(&target)
+                .build(),
+        );
+        assert!(entry.len() <= MAX_ENTRY_SIZE);
+        assert!(
+            entry.ends_with(" msg=\"msg\" truncated=true\n"),
+            "{}",
+            entry
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Opt-in `SIGHUP` handling to reopen log files, as expected by `logrotate` and friends.
+
+use crate::Handle;
+use std::io::{self, Read as _};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The write side of the self-pipe, or -1 if no handler has been installed.
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use libc::__errno_location as errno_location;
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+use libc::__error as errno_location;
+
+/// The signal handler, which wakes the helper thread. This must be async-signal-safe.
+extern "C" fn on_sighup(_: libc::c_int) {
+    let fd = WRITE_FD.load(Ordering::Relaxed);
+    let b = 0u8;
+
+    // If the write fails, the pipe is full, so the helper thread will wake anyway. Either way,
+    // restore `errno` so the interrupted code doesn't see a changed value.
+    // SAFETY: `write` is async-signal-safe, `b` is valid for a 1-byte read, and
+    // `errno_location` returns a valid pointer to this thread's `errno`.
+    unsafe {
+        let errno = *errno_location();
+        libc::write(fd, &b as *const u8 as *const libc::c_void, 1);
+        *errno_location() = errno;
+    }
+}
+
+/// Installs the `SIGHUP` handler and spawns a helper thread which calls `handle.reopen()`.
+///
+/// Fails with `ErrorKind::AlreadyExists` if called more than once.
+pub(crate) fn install(handle: Handle) -> io::Result<()> {
+    let (mut rx, tx) = UnixStream::pair()?;
+    tx.set_nonblocking(true)?;
+    if WRITE_FD
+        .compare_exchange(-1, tx.as_raw_fd(), Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "SIGHUP handler already installed",
+        ));
+    }
+    std::mem::forget(tx); // the signal handler uses it forever.
+
+    std::thread::Builder::new()
+        .name("logger-sighup".to_owned())
+        .spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok(n) = rx.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                handle.reopen();
+            }
+        })?;
+
+    // SAFETY: all-zero is a valid `sigaction`, and `on_sighup` is async-signal-safe.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::tempdir;
+    use crate::{Builder, Destination, LogFile};
+    use log::Log as _;
+    use std::time::{Duration, Instant};
+
+    /// Tests that a raised `SIGHUP` reopens the log file, as after `logrotate` renames it.
+    #[test]
+    fn reopen() {
+        let dir = tempdir("sighup");
+        let path = dir.join("log");
+        let h = Builder::new()
+            .spec("info")
+            .destination(Destination::File(LogFile::new(&path)))
+            .build();
+        h.reopen_on_sighup().unwrap();
+        let log = |msg| {
+            h.0.log(
+                &log::Record::builder()
+                    .args(format_args!("{}", msg))
+                    .level(log::Level::Info)
+                    .target("foo")
+                    .build(),
+            )
+        };
+        log("before");
+        std::fs::rename(&path, dir.join("log.1")).unwrap();
+
+        // SAFETY: `raise` is safe to call; the handler was installed above.
+        assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !path.exists() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        log("after");
+        let rotated = std::fs::read_to_string(dir.join("log.1")).unwrap();
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(rotated.ends_with(" foo] before\n"), "{}", rotated);
+        assert!(current.ends_with(" foo] after\n"), "{}", current);
+        assert!(!current.contains("before"), "{}", current);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
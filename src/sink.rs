@@ -0,0 +1,516 @@
+//! Sinks: destinations, each with its own format, color mode, and specification.
+
+use crate::async_buf::AsyncBuf;
+use crate::entry_buf::{with_entry_buf, EntryBuf, Writing};
+#[cfg(target_os = "linux")]
+use crate::journald;
+use crate::spec::Specification;
+use crate::{file, syslog, ColorMode, Destination, Format, FormatContext, Formatter};
+use log::{Metadata, Record};
+use std::io::{self, Write as _};
+use std::sync::Arc;
+
+/// The maximum number of sinks per logger, as they are tracked with a `u64` bitmask.
+const MAX_SINKS: usize = 64;
+
+/// A destination, along with how to format entries for it and which entries to send to it.
+///
+/// See `Builder::sink`.
+pub struct Sink {
+    dest: Destination,
+    fmt: Format,
+    pub(crate) formatter: Option<Arc<dyn Formatter>>,
+    color: ColorMode,
+    spec: Option<Specification>,
+}
+
+impl Sink {
+    /// Creates a sink for the given destination, with the default format (`Format::Google`)
+    /// and color mode (`ColorMode::Auto`).
+    pub fn new(dest: Destination) -> Self {
+        Sink {
+            dest,
+            fmt: Format::Google,
+            formatter: None,
+            color: ColorMode::Auto,
+            spec: None,
+        }
+    }
+
+    #[inline]
+    pub fn format(mut self, fmt: Format) -> Self {
+        self.fmt = fmt;
+        self
+    }
+
+    /// Uses a custom formatter rather than `format`.
+    ///
+    /// The formatter may use color as indicated by `FormatContext::use_color`.
+    #[inline]
+    pub fn formatter(mut self, formatter: impl Formatter) -> Self {
+        self.formatter = Some(Arc::new(formatter));
+        self
+    }
+
+    /// Sets color mode; default is auto.
+    #[inline]
+    pub fn color(mut self, color: ColorMode) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Restricts this sink to entries enabled by `spec` as well as the logger's own spec.
+    ///
+    /// By default, a sink receives all entries enabled by the logger's spec.
+    #[inline]
+    pub fn spec(mut self, spec: &str) -> Self {
+        self.spec = Some(Specification::new(spec));
+        self
+    }
+
+    fn use_color(&self) -> bool {
+        let colorable = self.formatter.is_some() || self.fmt == Format::Google;
+        if !colorable || self.color == ColorMode::Never {
+            false
+        } else if self.color == ColorMode::Always {
+            true
+        } else {
+            match self.dest {
+                Destination::Stderr => unsafe { libc::isatty(2) == 1 },
+                Destination::Stdout => unsafe { libc::isatty(1) == 1 },
+                #[cfg(target_os = "linux")]
+                Destination::Journald => false,
+                Destination::Syslog(_) | Destination::File(_) => false,
+            }
+        }
+    }
+}
+
+/// The opened sinks of a `Logger`, along with the distinct encoders they use.
+pub(crate) struct Sinks {
+    sinks: Vec<OpenSink>,
+
+    /// Each distinct encoder, with a bitmask of the sinks which use it.
+    encoders: Vec<(Encoder, u64)>,
+
+    /// The maximum size of an entry, including the trailing `\n`.
+    max_entry_size: usize,
+}
+
+/// An opened `Sink`.
+struct OpenSink {
+    out: Output,
+    spec: Option<Specification>,
+}
+
+/// A distinct way of encoding entries, shared by all sinks which use it.
+enum Encoder {
+    /// A `Format`, with its options.
+    Text(Format, FormatContext),
+
+    /// A custom formatter, with its options.
+    Custom(Arc<dyn Formatter>, FormatContext),
+
+    #[cfg(target_os = "linux")]
+    Journald,
+
+    /// Syslog, as configured for the sink with the given index.
+    Syslog(usize),
+}
+
+impl Sinks {
+    /// Opens the given sinks, applying `Specification::legacy_prefix_matching` to their specs.
+    /// Each sink's entries are formatted according to `ctx`, with `use_color` set per sink.
+    ///
+    /// Panics if there are more than 64. Returns an error if a destination can't be opened.
+    pub(crate) fn open(
+        sinks: Vec<Sink>,
+        legacy_prefix_matching: bool,
+        max_entry_size: usize,
+        ctx: FormatContext,
+    ) -> io::Result<Self> {
+        assert!(
+            sinks.len() <= MAX_SINKS,
+            "at most {} sinks are supported",
+            MAX_SINKS
+        );
+        let mut opened = Vec::with_capacity(sinks.len());
+        let mut encoders: Vec<(Encoder, u64)> = Vec::new();
+        for (i, sink) in sinks.into_iter().enumerate() {
+            let ctx = FormatContext {
+                use_color: sink.use_color(),
+                ..ctx
+            };
+            let encoder = match sink.dest {
+                #[cfg(target_os = "linux")]
+                Destination::Journald => Encoder::Journald,
+                Destination::Syslog(_) => Encoder::Syslog(i),
+                _ => match sink.formatter {
+                    Some(f) => Encoder::Custom(f, ctx),
+                    None => Encoder::Text(sink.fmt, ctx),
+                },
+            };
+            match encoders.iter_mut().find(|(e, _)| *e == encoder) {
+                Some((_, mask)) => *mask |= 1 << i,
+                None => encoders.push((encoder, 1 << i)),
+            }
+            opened.push(OpenSink {
+                out: Output::open(sink.dest)?,
+                spec: sink
+                    .spec
+                    .map(|s| s.or_legacy_prefix_matching(legacy_prefix_matching)),
+            });
+        }
+        Ok(Sinks {
+            sinks: opened,
+            encoders,
+            max_entry_size,
+        })
+    }
+
+    /// Returns a bitmask of all sinks.
+    pub(crate) fn all(&self) -> u64 {
+        match self.sinks.len() {
+            MAX_SINKS => u64::MAX,
+            n => (1 << n) - 1,
+        }
+    }
+
+    /// Returns the most detailed level any sink could accept.
+    pub(crate) fn max_level(&self) -> log::LevelFilter {
+        self.sinks
+            .iter()
+            .map(|s| s.spec.as_ref().map_or(log::LevelFilter::max(), |s| s.max))
+            .max()
+            .unwrap_or(log::LevelFilter::Off)
+    }
+
+    /// Returns a bitmask of the sinks whose spec enables the given target and level.
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> u64 {
+        let mut mask = 0;
+        for (i, s) in self.sinks.iter().enumerate() {
+            if s.spec
+                .as_ref()
+                .map_or(true, |s| s.get_level(metadata.target()) >= metadata.level())
+            {
+                mask |= 1 << i;
+            }
+        }
+        mask
+    }
+
+    /// Returns the subset of `mask` whose spec's message filter (if any) matches `record`.
+    pub(crate) fn filter(&self, record: &Record, mut mask: u64) -> u64 {
+        for (i, s) in self.sinks.iter().enumerate() {
+            if let Some(ref spec) = s.spec {
+                if mask & (1 << i) != 0 && !spec.is_match(record.args()) {
+                    mask &= !(1 << i);
+                }
+            }
+        }
+        mask
+    }
+
+    /// Formats `record` once per distinct encoder used by the sinks in `mask`, calling `emit`
+    /// with each entry and the bitmask of sinks it is for.
+    pub(crate) fn format(&self, record: &Record, mask: u64, mut emit: impl FnMut(&[u8], u64)) {
+        for (encoder, encoder_mask) in &self.encoders {
+            let mask = mask & encoder_mask;
+            if mask == 0 {
+                continue;
+            }
+
+            // Always write into an EntryBuf first. This minimizes thread contention, whether
+            // async is enabled or not.
+            with_entry_buf(self.max_entry_size, |mut buf| {
+                // Write as much as fits; ignore truncation, which is the only possible error.
+                let _ = encoder.write(&self.sinks, record, &mut buf);
+                let buf = buf.terminate();
+                emit(buf.get(), mask);
+            });
+        }
+    }
+
+    /// Writes a single entry to each sink in `mask`.
+    ///
+    /// If `is_test`, uses the `print!` and `eprint!` macros for stdout and stderr.
+    pub(crate) fn write_entry(&self, entry: &[u8], mask: u64, is_test: bool) {
+        for (i, s) in self.sinks.iter().enumerate() {
+            if mask & (1 << i) == 0 {
+                continue;
+            }
+            match s.out {
+                Output::Stderr if is_test => eprint!("{}", String::from_utf8_lossy(entry)),
+                Output::Stdout if is_test => print!("{}", String::from_utf8_lossy(entry)),
+                _ => {
+                    // This can throw an error, but what are we going to do, log it? Discard.
+                    let _ = s.out.write_entry(entry);
+                }
+            }
+        }
+    }
+
+    /// Writes a batch of entries to their sinks.
+    ///
+    /// `scratch` is used to hold the subset of entries destined for a sink which doesn't
+    /// receive all of them.
+    pub(crate) fn write_batch(&self, batch: &AsyncBuf, scratch: &mut AsyncBuf) {
+        for (i, s) in self.sinks.iter().enumerate() {
+            let bit = 1 << i;
+            let batch = if batch.masks.iter().all(|m| m & bit != 0) {
+                batch
+            } else {
+                scratch.clear();
+                for (i, entry) in batch.entries().enumerate() {
+                    if batch.masks[i] & bit != 0 {
+                        scratch.push(entry, batch.masks[i], batch.levels[i], batch.seqs[i]);
+                    }
+                }
+                &*scratch
+            };
+            if !batch.is_empty() {
+                // This can throw an error, but what are we going to do, log it? Discard.
+                let _ = s.out.write_batch(batch);
+            }
+        }
+    }
+
+    /// Reopens file destinations; see `Handle::reopen`.
+    pub(crate) fn reopen(&self) {
+        for s in &self.sinks {
+            if let Output::File(ref f) = s.out {
+                f.reopen();
+            }
+        }
+    }
+}
+
+impl PartialEq for Encoder {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Encoder::Text(f1, c1), Encoder::Text(f2, c2)) => f1 == f2 && c1 == c2,
+            (Encoder::Custom(f1, c1), Encoder::Custom(f2, c2)) => Arc::ptr_eq(f1, f2) && c1 == c2,
+            #[cfg(target_os = "linux")]
+            (Encoder::Journald, Encoder::Journald) => true,
+            (Encoder::Syslog(i1), Encoder::Syslog(i2)) => i1 == i2,
+            _ => false,
+        }
+    }
+}
+
+impl Encoder {
+    fn write(
+        &self,
+        sinks: &[OpenSink],
+        record: &Record,
+        buf: &mut EntryBuf<Writing>,
+    ) -> Result<(), std::fmt::Error> {
+        match *self {
+            Encoder::Text(ref fmt, ref ctx) => fmt.write(ctx, record, buf),
+            Encoder::Custom(ref f, ref ctx) => f.format(record, ctx, buf),
+            #[cfg(target_os = "linux")]
+            Encoder::Journald => journald::write(record, buf),
+            Encoder::Syslog(i) => match sinks[i].out {
+                Output::Syslog(ref s) => s.write(record, buf),
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+/// An opened `Destination`.
+enum Output {
+    Stderr,
+    Stdout,
+    #[cfg(target_os = "linux")]
+    Journald(journald::Socket),
+    Syslog(syslog::Sender),
+    File(file::Writer),
+}
+
+impl Output {
+    /// Opens the destination, returning an error which describes it on failure.
+    fn open(dest: Destination) -> io::Result<Self> {
+        let context =
+            |what: String| move |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", what, e));
+        Ok(match dest {
+            Destination::Stderr => Output::Stderr,
+            Destination::Stdout => Output::Stdout,
+            #[cfg(target_os = "linux")]
+            Destination::Journald => Output::Journald(
+                journald::Socket::new(std::path::Path::new(journald::SOCKET_PATH))
+                    .map_err(context("unable to create journald socket".to_owned()))?,
+            ),
+            Destination::Syslog(config) => Output::Syslog(
+                syslog::Sender::open(config)
+                    .map_err(context("unable to open syslog socket".to_owned()))?,
+            ),
+            Destination::File(config) => {
+                let what = format!("unable to open log file {}", config.path().display());
+                Output::File(file::Writer::open(config).map_err(context(what))?)
+            }
+        })
+    }
+
+    /// Writes a single entry to the destination.
+    ///
+    /// When operating synchronously, called directly from `log`.
+    fn write_entry(&self, buf: &[u8]) -> Result<(), std::io::Error> {
+        match *self {
+            Output::Stderr => std::io::stderr().write_all(buf),
+            Output::Stdout => std::io::stdout().write_all(buf),
+            #[cfg(target_os = "linux")]
+            Output::Journald(ref s) => s.send(buf),
+            Output::Syslog(ref s) => s.send(buf),
+            Output::File(ref f) => f.write_entries(buf, &[buf.len()]),
+        }
+    }
+
+    /// Writes a batch of entries to the destination, all at once if it is stream-based.
+    ///
+    /// When operating asynchronously, called only from `run_async`.
+    fn write_batch(&self, batch: &AsyncBuf) -> Result<(), std::io::Error> {
+        match *self {
+            Output::Stderr => std::io::stderr().write_all(&batch.bytes),
+            Output::Stdout => std::io::stdout().write_all(&batch.bytes),
+            #[cfg(target_os = "linux")]
+            Output::Journald(ref s) => {
+                let mut result = Ok(());
+                for entry in batch.entries() {
+                    // Keep going; one entry's failure shouldn't lose the rest.
+                    if let Err(e) = s.send(entry) {
+                        result = Err(e);
+                    }
+                }
+                result
+            }
+            Output::Syslog(ref s) => s.send_batch(batch.entries()),
+            Output::File(ref f) => f.write_entries(&batch.bytes, &batch.ends),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sink, Sinks};
+    use crate::async_buf::AsyncBuf;
+    use crate::testutil::tempdir;
+    use crate::{ColorMode, Destination, Format, FormatContext, LogFile};
+
+    /// Tests that sinks sharing a format share an encoder, and that per-sink specs apply.
+    #[test]
+    fn shared_encoders() {
+        let dir = tempdir("sink");
+        let sinks = Sinks::open(
+            vec![
+                Sink::new(Destination::File(LogFile::new(dir.join("a")))).spec("info"),
+                Sink::new(Destination::File(LogFile::new(dir.join("b")))).format(Format::Json),
+                Sink::new(Destination::File(LogFile::new(dir.join("c")))).spec("warn"),
+            ],
+            false,
+            crate::MAX_ENTRY_SIZE,
+            FormatContext::default(),
+        )
+        .unwrap();
+        assert_eq!(sinks.encoders.len(), 2);
+        assert_eq!(sinks.max_level(), log::LevelFilter::Trace);
+        let metadata = |level| log::Metadata::builder().level(level).target("foo").build();
+        assert_eq!(sinks.enabled(&metadata(log::Level::Info)), 0b011);
+        assert_eq!(sinks.enabled(&metadata(log::Level::Warn)), 0b111);
+
+        let record = log::Record::builder()
+            .args(format_args!("hi"))
+            .level(log::Level::Warn)
+            .target("foo")
+            .build();
+        let mut emitted = Vec::new();
+        sinks.format(&record, 0b111, |entry, mask| {
+            emitted.push((String::from_utf8(entry.to_owned()).unwrap(), mask))
+        });
+        assert_eq!(emitted.len(), 2);
+        assert!(emitted[0].0.starts_with('W'));
+        assert_eq!(emitted[0].1, 0b101);
+        assert!(emitted[1].0.starts_with('{'));
+        assert_eq!(emitted[1].1, 0b010);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a custom formatter's entries are truncated like the built-in formats'.
+    #[test]
+    fn custom_formatter() {
+        struct Custom;
+
+        impl crate::Formatter for Custom {
+            fn format(
+                &self,
+                record: &log::Record,
+                ctx: &FormatContext,
+                out: &mut dyn std::fmt::Write,
+            ) -> std::fmt::Result {
+                write!(out, "custom ")?;
+                ctx.write_location(record, out)?;
+                write!(out, " {}", record.args())
+            }
+        }
+
+        let sinks = Sinks::open(
+            vec![Sink::new(Destination::Stderr)
+                .formatter(Custom)
+                .color(ColorMode::Never)],
+            false,
+            16,
+            FormatContext::default(),
+        )
+        .unwrap();
+        let record = log::Record::builder()
+            .args(format_args!("héllo world"))
+            .level(log::Level::Info)
+            .target("foo")
+            .build();
+        let mut emitted = Vec::new();
+        sinks.format(&record, 0b1, |entry, _| emitted.push(entry.to_owned()));
+        assert_eq!(emitted, [&b"custom foo h\xc3\xa9l\n"[..]]);
+    }
+
+    /// Tests that a batch is split among sinks according to each entry's mask.
+    #[test]
+    fn write_batch() {
+        let dir = tempdir("sink-batch");
+        let sinks = Sinks::open(
+            vec![
+                Sink::new(Destination::File(LogFile::new(dir.join("a")))),
+                Sink::new(Destination::File(LogFile::new(dir.join("b")))),
+            ],
+            false,
+            crate::MAX_ENTRY_SIZE,
+            FormatContext::default(),
+        )
+        .unwrap();
+        let mut batch = AsyncBuf::with_capacity(0);
+        batch.push(b"both\n", 0b11, log::Level::Info, 0);
+        batch.push(b"a\n", 0b01, log::Level::Info, 1);
+        batch.push(b"b\n", 0b10, log::Level::Info, 2);
+        let mut scratch = AsyncBuf::with_capacity(0);
+        sinks.write_batch(&batch, &mut scratch);
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"both\na\n");
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"both\nb\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a destination which can't be opened is reported with context.
+    #[test]
+    fn open_error() {
+        let path =
+            std::env::temp_dir().join(format!("mylog-sink-missing-{}/a", std::process::id()));
+        let e = Sinks::open(
+            vec![Sink::new(Destination::File(LogFile::new(&path)))],
+            false,
+            crate::MAX_ENTRY_SIZE,
+            FormatContext::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert!(e.to_string().contains(&*path.to_string_lossy()), "{}", e);
+    }
+}
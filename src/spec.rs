@@ -1,19 +1,180 @@
+//! Logging specifications, in the style of `env_logger`'s `RUST_LOG`.
+
 use log::LevelFilter;
 use std::io::{self, Write};
 
 /// A logging specification: controls which modules log at what level.
+///
+/// The syntax is a comma-separated list of directives, each one of:
+///
+/// * `level`, the default level for modules not matched by another directive.
+/// * `prefix=level`, the level for the module `prefix` and those within it.
+/// * `prefix`, equivalent to `prefix=trace`.
+///
+/// Prefixes match only at `::` boundaries: `crate1` matches `crate1` and `crate1::db` but not
+/// `crate10`. A `*` segment matches any single segment, so `*::db=debug` matches `crate1::db`
+/// and `crate2::db::pool`. When several directives match, the one with the most segments wins,
+/// preferring literal segments over `*`. See `legacy_prefix_matching` for the old behavior.
+///
+/// Levels are `off`, `error`, `warn`, `info`, `debug`, and `trace`, case-insensitive. If no
+/// directive supplies a default, it is `off`; an empty spec is equivalent to `error`.
+///
+/// As in `env_logger`, the directives may be followed by `/filter`, e.g. `info,hyper=debug/timeout`,
+/// to emit only entries whose message matches `filter`. With the `regex` feature (on by
+/// default), `filter` is a regular expression; otherwise, it's a substring.
+///
+/// `Display` produces a string which parses back into the same specification.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Specification {
     /// A list of (filter, prefix), with the most specific prefixes first.
     directives: Vec<(LevelFilter, String)>,
 
+    /// The same directives, as a trie for fast lookup when not in legacy mode.
+    trie: Node,
+
     /// The most detailed log level of any module.
-    pub max: LevelFilter,
+    pub(crate) max: LevelFilter,
+
+    filter: Option<Filter>,
+
+    legacy_prefix_matching: bool,
+}
+
+/// A message filter, from the part of a spec after `/`.
+#[derive(Clone, Debug)]
+struct Filter {
+    #[cfg(feature = "regex")]
+    inner: regex::Regex,
+
+    #[cfg(not(feature = "regex"))]
+    inner: String,
+}
+
+impl Filter {
+    #[cfg(feature = "regex")]
+    fn new(s: &str) -> Result<Self, String> {
+        regex::Regex::new(s)
+            .map(|inner| Filter { inner })
+            .map_err(|e| e.to_string())
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn new(s: &str) -> Result<Self, String> {
+        Ok(Filter {
+            inner: s.to_owned(),
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        self.inner.as_str()
+    }
+
+    #[cfg(feature = "regex")]
+    fn is_match(&self, msg: &str) -> bool {
+        self.inner.is_match(msg)
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn is_match(&self, msg: &str) -> bool {
+        msg.contains(&self.inner)
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Filter {}
+
+/// An error returned by `Specification::parse`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpecError {
+    offset: usize,
+    directive: String,
+
+    /// The reason the filter is invalid, or `None` if the directive has an unparseable level.
+    filter_error: Option<String>,
+}
+
+impl SpecError {
+    /// Returns the byte offset of the offending directive (or filter) within the spec.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the offending directive (or filter).
+    pub fn directive(&self) -> &str {
+        &self.directive
+    }
 }
 
+impl std::fmt::Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.filter_error {
+            None => write!(
+                f,
+                "logging directive {:?} at byte {} has unparseable log level",
+                self.directive, self.offset
+            ),
+            Some(ref e) => write!(
+                f,
+                "logging filter {:?} at byte {} is invalid: {}",
+                self.directive, self.offset, e
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
 impl Specification {
+    /// Parses `spec`, skipping (and reporting on stderr) directives with unparseable levels.
+    ///
+    /// Prefer `parse` to reject bad specs outright.
     pub fn new(spec: &str) -> Self {
+        Self::parse_with(spec, |e| {
+            let _ = writeln!(io::stderr(), "{}", e);
+            Ok(())
+        })
+        .expect("lenient parse can't fail")
+    }
+
+    /// Parses `spec`, failing on the first directive with an unparseable level.
+    pub fn parse(spec: &str) -> Result<Self, SpecError> {
+        Self::parse_with(spec, Err)
+    }
+
+    /// Parses `spec`, calling `on_error` for each directive with an unparseable level and
+    /// for an invalid filter. If that returns `Ok`, the directive or filter is skipped.
+    fn parse_with(
+        spec: &str,
+        mut on_error: impl FnMut(SpecError) -> Result<(), SpecError>,
+    ) -> Result<Self, SpecError> {
+        let (spec, filter) = match spec.find('/') {
+            Some(i) => {
+                let f = &spec[i + 1..];
+                let filter = match Filter::new(f) {
+                    Ok(f) => Some(f),
+                    Err(e) => {
+                        on_error(SpecError {
+                            offset: i + 1,
+                            directive: f.to_owned(),
+                            filter_error: Some(e),
+                        })?;
+                        None
+                    }
+                };
+                (&spec[..i], filter)
+            }
+            None => (spec, None),
+        };
         let mut directives: Vec<(LevelFilter, String)> = Vec::new();
+        let mut offset = 0;
         for d in spec.split(',') {
+            let d_offset = offset;
+            offset += d.len() + 1;
             if d.is_empty() {
                 continue;
             }
@@ -26,11 +187,11 @@ impl Specification {
                 (Some(p), Some(l)) => match l.parse() {
                     Ok(l) => (l, p.to_owned()),
                     Err(_) => {
-                        let _ = writeln!(
-                            io::stderr(),
-                            "logging directive {:?} has unparseable log level",
-                            d
-                        );
+                        on_error(SpecError {
+                            offset: d_offset,
+                            directive: d.to_owned(),
+                            filter_error: None,
+                        })?;
                         continue;
                     }
                 },
@@ -43,24 +204,204 @@ impl Specification {
             directives.push((LevelFilter::Error, String::new()));
         }
 
-        // Sort the prefixes: longest to shortest.
-        directives.sort_by_key(|(_, ref p)| usize::MAX - p.len());
+        // Sort the prefixes: most to fewest segments, then fewest to most globs, then longest
+        // to shortest (which matters only in legacy mode).
+        directives.sort_by_key(|(_, ref p)| {
+            let segments = if p.is_empty() {
+                0
+            } else {
+                p.split("::").count()
+            };
+            let globs = p.split("::").filter(|&s| s == "*").count();
+            (usize::MAX - segments, globs, usize::MAX - p.len())
+        });
         let max = directives
             .iter()
             .map(|&(level, _)| level)
             .max()
             .unwrap_or(LevelFilter::Off);
-        Specification { directives, max }
+        let mut trie = Node::default();
+        for (rank, (level, prefix)) in directives.iter().enumerate() {
+            trie.insert(prefix, rank, *level);
+        }
+        Ok(Specification {
+            directives,
+            trie,
+            max,
+            filter,
+            legacy_prefix_matching: false,
+        })
+    }
+
+    /// If true, prefixes without globs match modules by plain string prefix, as in earlier
+    /// versions: `crate1` matches `crate10` and `crate1_util` as well as `crate1::db`.
+    ///
+    /// This setting isn't represented in the `Display` output.
+    pub fn legacy_prefix_matching(mut self, legacy: bool) -> Self {
+        self.legacy_prefix_matching = legacy;
+        self
     }
 
+    /// Enables legacy prefix matching if `legacy`; otherwise leaves the setting as is.
+    pub(crate) fn or_legacy_prefix_matching(mut self, legacy: bool) -> Self {
+        self.legacy_prefix_matching |= legacy;
+        self
+    }
+
+    /// Returns the most detailed log level of any module.
+    pub fn max_level(&self) -> LevelFilter {
+        self.max
+    }
+
+    /// Iterates through the directives as `(prefix, level)`, most specific first.
+    ///
+    /// The default level, if any, has an empty prefix.
+    pub fn directives(&self) -> impl Iterator<Item = (&str, LevelFilter)> {
+        self.directives.iter().map(|(l, p)| (p.as_str(), *l))
+    }
+
+    /// Returns the message filter (the part after `/`), if any.
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_ref().map(Filter::as_str)
+    }
+
+    /// Returns true if `msg` matches the filter, or if there is none.
+    ///
+    /// This is meant to be checked after `get_level`, as it may need to format the message.
+    pub fn is_match(&self, msg: &std::fmt::Arguments<'_>) -> bool {
+        let filter = match self.filter {
+            None => return true,
+            Some(ref f) => f,
+        };
+        match msg.as_str() {
+            Some(s) => filter.is_match(s),
+            None => filter.is_match(&msg.to_string()),
+        }
+    }
+
+    /// Returns the level for the given module (or other target).
+    ///
+    /// This takes time proportional to the length of `module` (plus some for each `*`
+    /// directive which matches), regardless of the number of directives. In legacy mode, it
+    /// scans the directives linearly instead.
     pub fn get_level(&self, module: &str) -> LevelFilter {
+        if self.legacy_prefix_matching {
+            return self.get_level_linear(module);
+        }
+        let mut best = None;
+        self.trie.lookup(module.split("::"), &mut best);
+        best.map_or(LevelFilter::Off, |(_, level)| level)
+    }
+
+    /// Returns the level of the first (most specific) directive which matches.
+    fn get_level_linear(&self, module: &str) -> LevelFilter {
         for &(level, ref prefix) in &self.directives {
-            if module.starts_with(prefix) {
+            if self.matches(prefix, module) {
                 return level;
             }
         }
         LevelFilter::Off
     }
+
+    fn matches(&self, prefix: &str, module: &str) -> bool {
+        if prefix.is_empty() {
+            return true;
+        }
+        if self.legacy_prefix_matching && !prefix.contains('*') {
+            return module.starts_with(prefix);
+        }
+        let mut module_segments = module.split("::");
+        prefix
+            .split("::")
+            .all(|p| module_segments.next().is_some_and(|m| p == "*" || p == m))
+    }
+}
+
+/// A trie node for `Specification::get_level`, keyed on `::`-separated segments.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Node {
+    /// The rank (index within `Specification::directives`) and level of the directive whose
+    /// prefix ends at this node, if any.
+    directive: Option<(usize, LevelFilter)>,
+
+    /// Children for literal segments, sorted for binary search.
+    children: Vec<(Box<str>, Node)>,
+
+    /// The child for a `*` segment.
+    glob: Option<Box<Node>>,
+}
+
+impl Node {
+    fn insert(&mut self, prefix: &str, rank: usize, level: LevelFilter) {
+        let mut node = self;
+        if !prefix.is_empty() {
+            for segment in prefix.split("::") {
+                node = if segment == "*" {
+                    node.glob.get_or_insert_with(Default::default)
+                } else {
+                    let i = match node.children.binary_search_by(|(s, _)| (**s).cmp(segment)) {
+                        Ok(i) => i,
+                        Err(i) => {
+                            node.children.insert(i, (segment.into(), Node::default()));
+                            i
+                        }
+                    };
+                    &mut node.children[i].1
+                };
+            }
+        }
+
+        // If there are duplicate prefixes, the first (lowest-rank) one wins, as in a linear scan.
+        if node.directive.is_none() {
+            node.directive = Some((rank, level));
+        }
+    }
+
+    /// Updates `best` with the lowest-rank directive matching a prefix of `segments`.
+    ///
+    /// Choosing the lowest rank gives the same result as a linear scan over the sorted
+    /// directives.
+    fn lookup<'a>(
+        &self,
+        mut segments: impl Iterator<Item = &'a str> + Clone,
+        best: &mut Option<(usize, LevelFilter)>,
+    ) {
+        if let Some(d) = self.directive {
+            if best.map_or(true, |b| d.0 < b.0) {
+                *best = Some(d);
+            }
+        }
+        let segment = match segments.next() {
+            Some(s) => s,
+            None => return,
+        };
+        if let Ok(i) = self.children.binary_search_by(|(s, _)| (**s).cmp(segment)) {
+            self.children[i].1.lookup(segments.clone(), best);
+        }
+        if let Some(ref glob) = self.glob {
+            glob.lookup(segments, best);
+        }
+    }
+}
+
+impl std::fmt::Display for Specification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (level, prefix)) in self.directives.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            let level = level.as_str().to_ascii_lowercase();
+            if prefix.is_empty() {
+                f.write_str(&level)?;
+            } else {
+                write!(f, "{}={}", prefix, level)?;
+            }
+        }
+        if let Some(ref filter) = self.filter {
+            write!(f, "/{}", filter.as_str())?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -87,5 +428,126 @@ mod tests {
         );
         assert_eq!(spec.get_level("crate3"), LevelFilter::Trace);
         assert_eq!(spec.get_level("crate4"), LevelFilter::Info);
+        assert_eq!(
+            spec.to_string(),
+            "crate2::inner=trace,crate1=off,crate2=warn,crate3=trace,info"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let spec = Specification::parse("crate1=off,info,crate2::inner=trace,crate3").unwrap();
+        assert_eq!(
+            spec.directives().collect::<Vec<_>>(),
+            [
+                ("crate2::inner", LevelFilter::Trace),
+                ("crate1", LevelFilter::Off),
+                ("crate3", LevelFilter::Trace),
+                ("", LevelFilter::Info),
+            ]
+        );
+        assert_eq!(Specification::parse(&spec.to_string()).unwrap(), spec);
+    }
+
+    #[test]
+    fn boundaries() {
+        let spec = Specification::parse("off,crate1=info,crate2::in=debug").unwrap();
+        assert_eq!(spec.get_level("crate1"), LevelFilter::Info);
+        assert_eq!(spec.get_level("crate1::db"), LevelFilter::Info);
+        assert_eq!(spec.get_level("crate10"), LevelFilter::Off);
+        assert_eq!(spec.get_level("crate1_util"), LevelFilter::Off);
+        assert_eq!(spec.get_level("crate2::inner"), LevelFilter::Off);
+
+        let spec = spec.legacy_prefix_matching(true);
+        assert_eq!(spec.get_level("crate10"), LevelFilter::Info);
+        assert_eq!(spec.get_level("crate1_util"), LevelFilter::Info);
+        assert_eq!(spec.get_level("crate2::inner"), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn globs() {
+        let spec = Specification::parse("info,*::db=debug,crate1::db=warn,crate2=error").unwrap();
+        assert_eq!(spec.get_level("crate1::db"), LevelFilter::Warn);
+        assert_eq!(spec.get_level("crate2::db"), LevelFilter::Debug);
+        assert_eq!(spec.get_level("crate2::db::pool"), LevelFilter::Debug);
+        assert_eq!(spec.get_level("crate2::web"), LevelFilter::Error);
+        assert_eq!(spec.get_level("crate3::web::db"), LevelFilter::Info);
+        assert_eq!(spec.get_level("db"), LevelFilter::Info);
+    }
+
+    /// Tests that the trie agrees with a linear scan.
+    #[test]
+    fn trie() {
+        let spec = Specification::parse(
+            "info,a=warn,a::b=debug,a::b=error,*::b=trace,a::*=off,*::*::c=debug,*=error,a::b::c",
+        )
+        .unwrap();
+        for module in [
+            "a",
+            "a::b",
+            "a::b::c",
+            "a::b::c::d",
+            "a::x",
+            "a::x::c",
+            "x",
+            "x::b",
+            "x::b::c",
+            "ab",
+            "x::y::c",
+            "",
+        ] {
+            assert_eq!(
+                spec.get_level(module),
+                spec.get_level_linear(module),
+                "{}",
+                module
+            );
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let spec = Specification::parse("info,hyper=debug/timed out").unwrap();
+        assert_eq!(spec.get_level("hyper"), LevelFilter::Debug);
+        assert_eq!(spec.filter(), Some("timed out"));
+        assert!(spec.is_match(&format_args!("timed out")));
+        assert!(spec.is_match(&format_args!("{} timed out", "connection")));
+        assert!(!spec.is_match(&format_args!("connected")));
+        assert_eq!(spec.to_string(), "hyper=debug,info/timed out");
+        assert_eq!(Specification::parse(&spec.to_string()).unwrap(), spec);
+
+        let spec = Specification::parse("info").unwrap();
+        assert_eq!(spec.filter(), None);
+        assert!(spec.is_match(&format_args!("anything")));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_filter() {
+        let spec = Specification::parse("info/^time.*out$").unwrap();
+        assert!(spec.is_match(&format_args!("timed out")));
+        assert!(!spec.is_match(&format_args!("connection timed out")));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn bad_filter() {
+        let e = Specification::parse("info/a(").unwrap_err();
+        assert_eq!(e.offset(), 5);
+        assert_eq!(e.directive(), "a(");
+        assert_eq!(Specification::new("info/a(").filter(), None);
+    }
+
+    #[test]
+    fn bad_level() {
+        let spec = Specification::new("info,foo=bogus");
+        assert_eq!(spec.to_string(), "info");
+        let e = Specification::parse("info,,foo=bogus").unwrap_err();
+        assert_eq!(e.offset(), 6);
+        assert_eq!(e.directive(), "foo=bogus");
+        assert_eq!(
+            e.to_string(),
+            "logging directive \"foo=bogus\" at byte 6 has unparseable log level"
+        );
     }
 }
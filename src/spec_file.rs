@@ -0,0 +1,211 @@
+//! Watching a spec file with inotify; see `Builder::spec_file`.
+
+use crate::spec::Specification;
+use crate::Handle;
+use log::Log as _;
+use std::ffi::CString;
+use std::io::{self, Write as _};
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::io::{AsRawFd as _, FromRawFd as _, OwnedFd};
+use std::path::{Path, PathBuf};
+
+/// Reads and parses the spec file at `path`.
+///
+/// Directives may be separated by commas and/or whitespace, including newlines. The `/filter`
+/// suffix is taken verbatim (so it may contain spaces) up to the end of the file, less any
+/// trailing line ending.
+pub(crate) fn read(path: &Path) -> Result<Specification, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read spec file {}: {}", path.display(), e))?;
+    let (directives, filter) = match contents.find('/') {
+        Some(i) => (&contents[..i], Some(&contents[i..])),
+        None => (&contents[..], None),
+    };
+    let mut spec = directives
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(",");
+    if let Some(f) = filter {
+        spec.push_str(f.trim_end_matches(['\n', '\r']));
+    }
+    Specification::parse(&spec)
+        .map_err(|e| format!("unable to parse spec file {}: {}", path.display(), e))
+}
+
+/// Watches `path` and spawns a helper thread which applies its contents on each change.
+///
+/// This watches the parent directory rather than the file itself, so that it sees files which
+/// are replaced via rename, as config management tools typically do.
+pub(crate) fn watch(handle: Handle, path: PathBuf) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "spec file has no name"))?
+        .to_owned();
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let dir = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // SAFETY: `inotify_init1` has no preconditions; on success, the fd is ours to own.
+    let fd = unsafe {
+        let fd = libc::inotify_init1(libc::IN_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        OwnedFd::from_raw_fd(fd)
+    };
+
+    // SAFETY: `dir` is a valid NUL-terminated string.
+    if unsafe {
+        libc::inotify_add_watch(
+            fd.as_raw_fd(),
+            dir.as_ptr(),
+            libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    std::thread::Builder::new()
+        .name("logger-spec-file".to_owned())
+        .spawn(move || {
+            // `inotify_event` requires 4-byte alignment.
+            let mut buf = [0u32; 1024];
+            loop {
+                // SAFETY: `buf` is valid for writes of its full size.
+                let n = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        std::mem::size_of_val(&buf),
+                    )
+                };
+                if n < 0 {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    break;
+                }
+                // SAFETY: the kernel wrote `n` bytes.
+                let events =
+                    unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n as usize) };
+                if changed(events, name.as_bytes()) {
+                    match read(&path) {
+                        Ok(spec) => handle.set_specification(spec),
+
+                        // Report on stderr, as the current spec may filter out the log entry.
+                        // Log via `handle` rather than `log::error!`, as this logger may not
+                        // be the global one.
+                        Err(e) => {
+                            let _ = writeln!(io::stderr(), "{}; keeping previous spec", e);
+                            handle.0.log(
+                                &log::Record::builder()
+                                    .args(format_args!("{}; keeping previous spec", e))
+                                    .level(log::Level::Error)
+                                    .target(module_path!())
+                                    .module_path_static(Some(module_path!()))
+                                    .file_static(Some(file!()))
+                                    .line(Some(line!()))
+                                    .build(),
+                            );
+                        }
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Returns true if any of the given `inotify_event`s are for the file `name`.
+fn changed(mut events: &[u8], name: &[u8]) -> bool {
+    const HEADER_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+    let mut found = false;
+    while events.len() >= HEADER_LEN {
+        // SAFETY: the kernel writes complete events, and `read_unaligned` has no alignment
+        // requirement.
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(events.as_ptr() as *const libc::inotify_event) };
+        let end = HEADER_LEN + event.len as usize;
+        let event_name = &events[HEADER_LEN..end];
+
+        // The name is NUL-padded.
+        let event_name = match event_name.iter().position(|&b| b == 0) {
+            Some(i) => &event_name[..i],
+            None => event_name,
+        };
+        found |= event_name == name;
+        events = &events[end..];
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::tempdir;
+    use crate::{Builder, Destination, LogFile};
+    use log::Log as _;
+    use std::time::{Duration, Instant};
+
+    fn enabled(h: &crate::Handle, target: &str, level: log::Level) -> bool {
+        h.0.enabled(&log::Metadata::builder().level(level).target(target).build())
+    }
+
+    /// Waits up to 5 seconds for the watcher to pick up a change.
+    fn wait_for(f: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn read_filter_with_spaces() {
+        let dir = tempdir("spec-file-filter");
+        let path = dir.join("spec");
+        std::fs::write(&path, "warn\nfoo=debug, bar=info/disk full\n").unwrap();
+        let spec = super::read(&path).unwrap();
+        assert_eq!(spec.filter(), Some("disk full"));
+        assert_eq!(spec.get_level("foo"), log::LevelFilter::Debug);
+        assert_eq!(spec.get_level("bar"), log::LevelFilter::Info);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let dir = tempdir("spec-file");
+        let path = dir.join("spec");
+        let log_path = dir.join("log");
+        std::fs::write(&path, "info\nfoo=debug\n").unwrap();
+        let h = Builder::new()
+            .spec("warn")
+            .spec_file(&path)
+            .destination(Destination::File(LogFile::new(&log_path)))
+            .build();
+        assert!(enabled(&h, "foo", log::Level::Debug));
+        assert!(!enabled(&h, "bar", log::Level::Debug));
+
+        // Replace via rename, as config management tools do.
+        std::fs::write(dir.join("spec.tmp"), "info,bar=debug").unwrap();
+        std::fs::rename(dir.join("spec.tmp"), &path).unwrap();
+        wait_for(|| enabled(&h, "bar", log::Level::Debug));
+        assert!(!enabled(&h, "foo", log::Level::Debug));
+
+        // A parse error is logged and keeps the previous spec; a later fix is still picked up.
+        std::fs::write(&path, "info,bar=bogus").unwrap();
+        wait_for(|| {
+            std::fs::read_to_string(&log_path)
+                .unwrap()
+                .contains("unable to parse spec file")
+        });
+        assert!(enabled(&h, "bar", log::Level::Debug));
+        assert!(!enabled(&h, "foo", log::Level::Debug));
+        assert!(!enabled(&h, "baz", log::Level::Debug));
+        std::fs::write(&path, "trace").unwrap();
+        wait_for(|| enabled(&h, "baz", log::Level::Trace));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
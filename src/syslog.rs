@@ -0,0 +1,615 @@
+//! Destination for syslog daemons such as rsyslog, over a Unix socket, UDP, or TCP.
+
+use crate::entry_buf::{EntryBuf, Writing};
+use log::{Level, Record};
+use std::fmt::{Display, Write as _};
+use std::io::{self, Write as _};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Configuration for `Destination::Syslog`.
+///
+/// ```no_run
+/// let dest = mylog::Destination::Syslog(
+///     mylog::Syslog::tcp("192.0.2.1:601".parse().unwrap())
+///         .protocol(mylog::SyslogProtocol::Rfc5424)
+///         .facility(mylog::Facility::Local3)
+///         .app_name("moonfire-nvr"),
+/// );
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Syslog {
+    transport: Transport,
+    protocol: SyslogProtocol,
+    facility: Facility,
+    app_name: Option<String>,
+    hostname: Option<String>,
+    procid: Option<String>,
+    timeout: Duration,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Transport {
+    Unix(PathBuf),
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+/// The syslog message format.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyslogProtocol {
+    /// The traditional BSD format of [RFC 3164](https://www.rfc-editor.org/rfc/rfc3164):
+    /// ```text
+    /// <29>Mar  8 21:31:24 myhost moonfire-nvr[1234]: main moonfire_nvr] Success. camera_id=3
+    /// ```
+    ///
+    /// The hostname is omitted on Unix sockets, as with glibc's `syslog(3)`.
+    Rfc3164,
+
+    /// The newer format of [RFC 5424](https://www.rfc-editor.org/rfc/rfc5424), which has
+    /// precise timestamps and passes key-values as structured data:
+    /// ```text
+    /// <29>1 2021-03-08T21:31:24.255123-08:00 myhost moonfire-nvr 1234 - [kv@32473 camera_id="3"] main moonfire_nvr] Success.
+    /// ```
+    Rfc5424,
+}
+
+/// The syslog facility, which (along with the level) determines the priority.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+impl Syslog {
+    fn new(transport: Transport) -> Self {
+        Syslog {
+            transport,
+            protocol: SyslogProtocol::Rfc3164,
+            facility: Facility::User,
+            app_name: None,
+            hostname: None,
+            procid: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sends datagrams to a local Unix socket, typically `/dev/log`.
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::new(Transport::Unix(path.into()))
+    }
+
+    /// Sends datagrams to a UDP host:port, typically port 514.
+    pub fn udp(addr: SocketAddr) -> Self {
+        Self::new(Transport::Udp(addr))
+    }
+
+    /// Sends to a TCP host:port using octet-counting framing
+    /// ([RFC 6587](https://www.rfc-editor.org/rfc/rfc6587#section-3.4.1)), reconnecting as
+    /// necessary.
+    pub fn tcp(addr: SocketAddr) -> Self {
+        Self::new(Transport::Tcp(addr))
+    }
+
+    /// Sets the message format; default is RFC 3164.
+    pub fn protocol(mut self, protocol: SyslogProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the facility; default is `user`.
+    pub fn facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    /// Sets the app-name (or RFC 3164 tag); default is the program name.
+    pub fn app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.app_name = Some(app_name.into());
+        self
+    }
+
+    /// Sets the hostname; default is the result of `gethostname(2)`.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Sets the procid; default is the process id.
+    pub fn procid(mut self, procid: impl Into<String>) -> Self {
+        self.procid = Some(procid.into());
+        self
+    }
+
+    /// Sets the TCP connect and write timeout; default is 5 seconds.
+    ///
+    /// An unresponsive server stalls logging for at most this long per attempt; the batch is
+    /// then dropped and the connection retried with the next one.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Default for Syslog {
+    /// Sends to `/dev/log`.
+    fn default() -> Self {
+        Self::unix("/dev/log")
+    }
+}
+
+/// An opened `Syslog` destination.
+pub(crate) struct Sender {
+    protocol: SyslogProtocol,
+    facility: Facility,
+    app_name: String,
+
+    /// The hostname, or `None` if it should be omitted from RFC 3164 messages.
+    hostname: Option<String>,
+    procid: String,
+    conn: Conn,
+}
+
+enum Conn {
+    Unix(UnixDatagram, PathBuf),
+    Udp(UdpSocket, SocketAddr),
+    Tcp(Mutex<Option<TcpStream>>, SocketAddr, Duration),
+}
+
+impl Sender {
+    pub(crate) fn open(config: Syslog) -> io::Result<Self> {
+        let omit_hostname = config.protocol == SyslogProtocol::Rfc3164
+            && matches!(config.transport, Transport::Unix(_));
+        let conn = match config.transport {
+            Transport::Unix(path) => Conn::Unix(UnixDatagram::unbound()?, path),
+            Transport::Udp(addr) => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                Conn::Udp(UdpSocket::bind(local)?, addr)
+            }
+            Transport::Tcp(addr) => Conn::Tcp(Mutex::new(None), addr, config.timeout),
+        };
+        Ok(Sender {
+            protocol: config.protocol,
+            facility: config.facility,
+            app_name: config.app_name.unwrap_or_else(program_name),
+            hostname: match config.hostname {
+                Some(h) => Some(h),
+                None if omit_hostname => None,
+                None => Some(hostname()),
+            },
+            procid: config
+                .procid
+                .unwrap_or_else(|| std::process::id().to_string()),
+            conn,
+        })
+    }
+
+    /// Writes `record` as a syslog message.
+    ///
+    /// The body is as in `Format::GoogleSystemd`, minus the priority prefix.
+    pub(crate) fn write(
+        &self,
+        record: &Record,
+        buf: &mut EntryBuf<Writing>,
+    ) -> Result<(), std::fmt::Error> {
+        let pri = (self.facility as u8) * 8 + severity(record.level());
+        let t = thread::current();
+        match self.protocol {
+            SyslogProtocol::Rfc3164 => {
+                write!(
+                    buf,
+                    "<{}>{} ",
+                    pri,
+                    jiff::Zoned::now().strftime("%b %e %H:%M:%S")
+                )?;
+                if let Some(ref h) = self.hostname {
+                    write!(buf, "{} ", h)?;
+                }
+                write!(buf, "{}[{}]: ", self.app_name, self.procid)?;
+                write_body(&t, record, buf)?;
+                crate::kv::write_pairs(record, buf)
+            }
+            SyslogProtocol::Rfc5424 => {
+                write!(
+                    buf,
+                    "<{}>1 {} {} {} {} - ",
+                    pri,
+                    jiff::Zoned::now().strftime("%Y-%m-%dT%H:%M:%S%.6f%:z"),
+                    Header(self.hostname.as_deref().unwrap_or("-"), 255),
+                    Header(&self.app_name, 48),
+                    Header(&self.procid, 128),
+                )?;
+                write_structured_data(record, buf)?;
+                buf.write_str(" ")?;
+                write_body(&t, record, buf)
+            }
+        }
+    }
+
+    /// Sends a single entry as written by `write`.
+    pub(crate) fn send(&self, entry: &[u8]) -> io::Result<()> {
+        let msg = strip_newline(entry);
+        match self.conn {
+            Conn::Unix(ref s, ref path) => s.send_to(msg, path).map(|_| ()),
+            Conn::Udp(ref s, addr) => s.send_to(msg, addr).map(|_| ()),
+            Conn::Tcp(..) => self.send_tcp(&frame(std::iter::once(entry))),
+        }
+    }
+
+    /// Sends a batch of entries, each as written by `write`.
+    pub(crate) fn send_batch<'a>(&self, entries: impl Iterator<Item = &'a [u8]>) -> io::Result<()> {
+        if let Conn::Tcp(..) = self.conn {
+            return self.send_tcp(&frame(entries));
+        }
+        let mut result = Ok(());
+        for entry in entries {
+            // Keep going; one entry's failure shouldn't lose the rest.
+            if let Err(e) = self.send(entry) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Sends already-framed data over TCP, (re)connecting if necessary and retrying once.
+    fn send_tcp(&self, framed: &[u8]) -> io::Result<()> {
+        let (stream, addr, timeout) = match self.conn {
+            Conn::Tcp(ref stream, addr, timeout) => (stream, addr, timeout),
+            _ => unreachable!(),
+        };
+        let mut stream = stream.lock().unwrap();
+        let mut retried = false;
+        loop {
+            let s = match *stream {
+                Some(ref mut s) => s,
+                None => {
+                    let s = TcpStream::connect_timeout(&addr, timeout)?;
+                    s.set_write_timeout(Some(timeout))?;
+                    stream.insert(s)
+                }
+            };
+            match s.write_all(framed) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    *stream = None;
+                    if retried {
+                        return Err(e);
+                    }
+                    retried = true;
+                }
+            }
+        }
+    }
+}
+
+/// Maps a level to a syslog severity, as `Format::GoogleSystemd` does.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error if crate::fatal::is_fatal() => 2, // LOG_CRIT
+        Level::Error => 3,                             // LOG_ERR
+        Level::Warn => 4,                              // LOG_WARNING
+        Level::Info => 5,                              // LOG_NOTICE
+        Level::Debug => 6,                             // LOG_INFO
+        Level::Trace => 7,                             // LOG_DEBUG
+    }
+}
+
+fn write_body(
+    t: &thread::Thread,
+    record: &Record,
+    buf: &mut EntryBuf<Writing>,
+) -> Result<(), std::fmt::Error> {
+    let target = record.target();
+    match t.name() {
+        Some(name) => write!(buf, "{} {}] {}", name, target, record.args()),
+        None => write!(buf, "{:?} {}] {}", t.id(), target, record.args()),
+    }
+}
+
+/// The SD-ID used for key-values.
+///
+/// Custom SD-IDs must have the form `name@<private enterprise number>`; this uses the number
+/// reserved for documentation by [RFC 5612](https://www.rfc-editor.org/rfc/rfc5612).
+const KV_SD_ID: &str = "kv@32473";
+
+/// Writes key-values as an RFC 5424 structured data element, or `-` if there are none.
+///
+/// On truncation, params which don't fit are omitted so the element is still well-formed and
+/// the message can follow. If not even the SD-ID fits, this writes `-`.
+fn write_structured_data(
+    record: &Record,
+    buf: &mut EntryBuf<Writing>,
+) -> Result<(), std::fmt::Error> {
+    if record.key_values().count() == 0 {
+        return buf.write_str("-");
+    }
+    let start = buf.len();
+    buf.set_reserved(1); // for the closing `]`.
+    let result = write!(buf, "[{}", KV_SD_ID).and_then(|()| {
+        crate::kv::for_each(record, |k, v| {
+            let mark = buf.len();
+            let result = write!(buf, " {}=\"", Header(k.as_str(), 32))
+                .and_then(|()| write!(ParamEscaper(buf), "{}", v))
+                .and_then(|()| buf.write_str("\""));
+            if result.is_err() {
+                buf.rollback(mark);
+            }
+            Ok(())
+        })
+    });
+    buf.set_reserved(0);
+    if result.is_err() {
+        buf.rollback(start);
+        return buf.write_str("-");
+    }
+    buf.write_str("]")
+}
+
+/// Writes an RFC 5424 header field or SD-NAME: printable ASCII excluding space, `=`, `]`, and
+/// `"`, at most the given length. Other characters are replaced with `_`.
+struct Header<'a>(&'a str, usize);
+
+impl Display for Header<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("-");
+        }
+        for c in self.0.chars().take(self.1) {
+            f.write_char(match c {
+                '!'..='~' if !matches!(c, '=' | ']' | '"') => c,
+                _ => '_',
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes `"`, `\`, and `]` within an RFC 5424 PARAM-VALUE.
+struct ParamEscaper<'a, 'b>(&'a mut EntryBuf<'b, Writing>);
+
+impl std::fmt::Write for ParamEscaper<'_, '_> {
+    fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
+        let mut start = 0;
+        for (i, b) in s.bytes().enumerate() {
+            if matches!(b, b'"' | b'\\' | b']') {
+                self.0.write_str(&s[start..i])?;
+                self.0.write_whole(if b == b'"' {
+                    "\\\""
+                } else if b == b'\\' {
+                    "\\\\"
+                } else {
+                    "\\]"
+                })?;
+                start = i + 1;
+            }
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+/// Strips the trailing newline added by `EntryBuf::terminate`; syslog messages are framed by
+/// the datagram or octet count instead.
+fn strip_newline(entry: &[u8]) -> &[u8] {
+    entry.strip_suffix(b"\n").unwrap_or(entry)
+}
+
+/// Frames entries with octet counting: `MSG-LEN SP SYSLOG-MSG`.
+fn frame<'a>(entries: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut framed = Vec::new();
+    for entry in entries {
+        let msg = strip_newline(entry);
+        let _ = write!(framed, "{} ", msg.len());
+        framed.extend_from_slice(msg);
+    }
+    framed
+}
+
+/// Returns the program name, for use as the default app-name.
+fn program_name() -> String {
+    std::env::args_os()
+        .next()
+        .as_ref()
+        .and_then(|a| std::path::Path::new(a).file_name())
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+/// Returns the hostname, for use as the default.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for writes of its length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "-".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Facility, Sender, Syslog, SyslogProtocol};
+    use crate::testutil::format_bytes;
+    use std::io::Read as _;
+    use std::time::{Duration, Instant};
+
+    fn format(sender: &Sender, record: &log::Record) -> Vec<u8> {
+        format_bytes(crate::MAX_ENTRY_SIZE, |buf| sender.write(record, buf))
+    }
+
+    fn record_with<'a>(
+        args: std::fmt::Arguments<'a>,
+        kvs: &'a dyn log::kv::Source,
+    ) -> log::Record<'a> {
+        log::Record::builder()
+            .args(args)
+            .level(log::Level::Info)
+            .target("moonfire_nvr")
+            .key_values(kvs)
+            .build()
+    }
+
+    #[test]
+    fn rfc3164() {
+        let sender = Sender::open(
+            Syslog::udp("127.0.0.1:514".parse().unwrap())
+                .facility(Facility::Daemon)
+                .app_name("nvr")
+                .hostname("myhost")
+                .procid("42"),
+        )
+        .unwrap();
+        let kvs = [("camera_id", 3)];
+        let entry = format(&sender, &record_with(format_args!("Success."), &kvs));
+        let entry = String::from_utf8(entry).unwrap();
+        assert!(entry.starts_with("<29>"), "{}", entry);
+        assert!(entry.contains(" myhost nvr[42]: "), "{}", entry);
+        assert!(
+            entry.ends_with(" moonfire_nvr] Success. camera_id=3\n"),
+            "{}",
+            entry
+        );
+    }
+
+    #[test]
+    fn rfc3164_unix_omits_hostname() {
+        let sender = Sender::open(Syslog::default().app_name("nvr").procid("42")).unwrap();
+        let kvs: [(&str, i32); 0] = [];
+        let entry = format(&sender, &record_with(format_args!("hi"), &kvs));
+        let entry = String::from_utf8(entry).unwrap();
+        // <13>Mar  8 21:31:24 nvr[42]: ...
+        assert_eq!(&entry[20..28], "nvr[42]:", "{}", entry);
+    }
+
+    #[test]
+    fn rfc5424() {
+        let sender = Sender::open(
+            Syslog::udp("127.0.0.1:514".parse().unwrap())
+                .protocol(SyslogProtocol::Rfc5424)
+                .facility(Facility::Local3)
+                .app_name("moonfire nvr")
+                .hostname("myhost")
+                .procid("42"),
+        )
+        .unwrap();
+        let name = "a \"quoted\" ]";
+        let kvs: [(&str, log::kv::Value); 2] = [
+            ("camera_id", 3.into()),
+            ("name", log::kv::Value::from_display(&name)),
+        ];
+        let entry = format(&sender, &record_with(format_args!("Success."), &kvs));
+        let entry = String::from_utf8(entry).unwrap();
+        assert!(entry.starts_with("<157>1 "), "{}", entry);
+        assert!(
+            entry.contains(
+                " myhost moonfire_nvr 42 - [kv@32473 camera_id=\"3\" \
+                 name=\"a \\\"quoted\\\" \\]\"] "
+            ),
+            "{}",
+            entry
+        );
+        assert!(entry.ends_with(" moonfire_nvr] Success.\n"), "{}", entry);
+    }
+
+    #[test]
+    fn rfc5424_truncated() {
+        let sender = Sender::open(
+            Syslog::udp("127.0.0.1:514".parse().unwrap())
+                .protocol(SyslogProtocol::Rfc5424)
+                .app_name("nvr")
+                .hostname("myhost")
+                .procid("42"),
+        )
+        .unwrap();
+        let big = "x".repeat(500);
+        let kvs = [("camera_id", "3"), ("big", &big[..]), ("stream", "main")];
+        let record = record_with(format_args!("Success."), &kvs);
+        let entry = format_bytes(200, |buf| sender.write(&record, buf));
+        let entry = String::from_utf8(entry).unwrap();
+        assert!(entry.len() <= 200);
+        assert!(
+            entry.contains(" nvr 42 - [kv@32473 camera_id=\"3\" stream=\"main\"] "),
+            "{}",
+            entry
+        );
+        assert!(entry.ends_with(" moonfire_nvr] Success.\n"), "{}", entry);
+    }
+
+    #[test]
+    fn udp() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = Sender::open(Syslog::udp(server.local_addr().unwrap())).unwrap();
+        sender
+            .send_batch([&b"<13>a\n"[..], &b"<13>b\n"[..]].iter().copied())
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<13>a");
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<13>b");
+    }
+
+    #[test]
+    fn unix() {
+        let path = std::env::temp_dir().join(format!("mylog-syslog-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let sender = Sender::open(Syslog::unix(&path)).unwrap();
+        sender.send(b"<13>a\n").unwrap();
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<13>a");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tcp_octet_counting() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = Sender::open(Syslog::tcp(listener.local_addr().unwrap())).unwrap();
+        sender
+            .send_batch([&b"<13>a\n"[..], &b"<13>bc\n"[..]].iter().copied())
+            .unwrap();
+        drop(sender);
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        conn.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "5 <13>a6 <13>bc");
+    }
+
+    #[test]
+    fn tcp_connect_timeout() {
+        // A non-routable address, so the connection attempt hangs rather than failing fast.
+        let sender = Sender::open(
+            Syslog::tcp("192.0.2.1:601".parse().unwrap()).timeout(Duration::from_millis(50)),
+        )
+        .unwrap();
+        let start = Instant::now();
+        sender.send(b"<13>a\n").unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,264 @@
+//! `Format::Template`: entries laid out according to a pattern string.
+
+use crate::entry_buf::{EntryBuf, Writing};
+use crate::FormatContext;
+use log::{Level, Record};
+use std::fmt::Write as _;
+
+/// A pattern for `Format::Template`, parsed once into a list of segments.
+///
+/// Parse with `str::parse`. Text outside of braces is copied as-is; `{{` and `}}` are literal
+/// braces. Placeholders:
+///
+/// * `{level}`: the lowercase level, e.g. `info`, or `fatal` for `fatal!`.
+/// * `{level:1}`: the level as a single letter, as in `Format::Google`, e.g. `I`.
+/// * `{time}`: the local time as RFC 3339 with microseconds, e.g.
+///   `2021-03-08T21:31:24.255123-08:00`.
+/// * `{time:FORMAT}`: the local time with the given `strftime`-style format, as supported by
+///   [`jiff`](https://docs.rs/jiff/latest/jiff/fmt/strtime/index.html), e.g.
+///   `{time:%H:%M:%S%.6f}`.
+/// * `{thread}`: the thread, as configured by `Builder::thread`.
+/// * `{tid}`: the kernel thread ID; see `ThreadColumn::Tid`.
+/// * `{target}`: the log target, usually a module path.
+/// * `{module}`: the module path, or nothing if unknown.
+/// * `{file}`: the source file, or nothing if unknown. Honors `Builder::file_basename`.
+/// * `{line}`: the source line, or nothing if unknown.
+/// * `{msg}`: the message supplied to the log macro.
+/// * `{kv}`: the key-values attached to the record, as space-separated `key=value` pairs
+///   written as in the logfmt format.
+///
+/// ```
+/// let fmt = mylog::Format::Template(
+///     "{level:1}{time:%H:%M:%S%.6f} {tid} {target}:{line}] {msg} {kv}"
+///         .parse()
+///         .unwrap(),
+/// );
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Level,
+    LevelLetter,
+
+    /// A `strftime`-style format.
+    Time(String),
+
+    Thread,
+    Tid,
+    Target,
+    Module,
+    File,
+    Line,
+    Msg,
+    Kv,
+}
+
+/// The format of `{time}` without an explicit format.
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
+
+/// An error returned when parsing a `Template`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TemplateError {
+    offset: usize,
+    reason: String,
+}
+
+impl TemplateError {
+    /// Returns the byte offset of the offending placeholder or brace within the pattern.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "template error at byte {}: {}", self.offset, self.reason)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl std::str::FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |offset, reason: String| TemplateError { offset, reason };
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut pos = 0;
+        while let Some(i) = s[pos..].find(['{', '}']).map(|i| pos + i) {
+            literal.push_str(&s[pos..i]);
+            let brace = &s[i..i + 1];
+            if s[i + 1..].starts_with(brace) {
+                literal.push_str(brace);
+                pos = i + 2;
+                continue;
+            }
+            if brace == "}" {
+                return Err(err(i, "unmatched `}`".to_owned()));
+            }
+            let end = match s[i..].find('}') {
+                Some(e) => i + e,
+                None => return Err(err(i, "unterminated placeholder".to_owned())),
+            };
+            let segment = match s[i + 1..end].split_once(':') {
+                None => match &s[i + 1..end] {
+                    "level" => Segment::Level,
+                    "time" => Segment::Time(DEFAULT_TIME_FORMAT.to_owned()),
+                    "thread" => Segment::Thread,
+                    "tid" => Segment::Tid,
+                    "target" => Segment::Target,
+                    "module" => Segment::Module,
+                    "file" => Segment::File,
+                    "line" => Segment::Line,
+                    "msg" => Segment::Msg,
+                    "kv" => Segment::Kv,
+                    p => return Err(err(i, format!("unknown placeholder {:?}", p))),
+                },
+                Some(("level", "1")) => Segment::LevelLetter,
+                Some(("time", f)) => {
+                    if let Err(e) = jiff::fmt::strtime::format(f, &jiff::Zoned::now()) {
+                        return Err(err(i, format!("invalid time format {:?}: {}", f, e)));
+                    }
+                    Segment::Time(f.to_owned())
+                }
+                Some(_) => return Err(err(i, format!("unknown placeholder {:?}", &s[i + 1..end]))),
+            };
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(segment);
+            pos = end + 1;
+        }
+        literal.push_str(&s[pos..]);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+}
+
+impl Template {
+    pub(crate) fn write(
+        &self,
+        ctx: &FormatContext,
+        record: &Record,
+        buf: &mut EntryBuf<Writing>,
+    ) -> Result<(), std::fmt::Error> {
+        // Get the time at most once, even if there are several time segments.
+        let mut now = None;
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(l) => buf.write_str(l)?,
+                Segment::Level => buf.write_str(crate::json::level_str(record.level()))?,
+                Segment::LevelLetter => buf.write_char(level_letter(record.level()))?,
+                Segment::Time(f) => {
+                    let now = now.get_or_insert_with(jiff::Zoned::now);
+                    write!(buf, "{}", now.strftime(f))?;
+                }
+                Segment::Thread => ctx.write_thread(buf)?,
+                Segment::Tid => crate::write_tid(buf)?,
+                Segment::Target => buf.write_str(record.target())?,
+                Segment::Module => buf.write_str(record.module_path().unwrap_or(""))?,
+                Segment::File => buf.write_str(ctx.file(record).unwrap_or(""))?,
+                Segment::Line => {
+                    if let Some(line) = record.line() {
+                        write!(buf, "{}", line)?;
+                    }
+                }
+                Segment::Msg => write!(buf, "{}", record.args())?,
+                Segment::Kv => {
+                    let mut first = true;
+                    crate::kv::for_each(record, |k, v| {
+                        if !std::mem::take(&mut first) {
+                            buf.write_str(" ")?;
+                        }
+                        crate::logfmt::write_key(buf, k.as_str())?;
+                        buf.write_str("=")?;
+                        crate::logfmt::write_value(buf, v)
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the level as a single letter, as in `Format::Google`.
+fn level_letter(level: Level) -> char {
+    match level {
+        Level::Error if crate::fatal::is_fatal() => 'F',
+        Level::Error => 'E',
+        Level::Warn => 'W',
+        Level::Info => 'I',
+        Level::Debug => 'D',
+        Level::Trace => 'T',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Segment, Template};
+    use crate::{testutil, Format, FormatContext};
+
+    fn format(template: &str, record: &log::Record) -> String {
+        let fmt = Format::Template(template.parse().unwrap());
+        testutil::format(crate::MAX_ENTRY_SIZE, |buf| {
+            fmt.write(&FormatContext::default(), record, buf)
+        })
+    }
+
+    #[test]
+    fn parse() {
+        let t: Template = "{{{level:1}}} {msg}".parse().unwrap();
+        assert_eq!(
+            t.segments,
+            [
+                Segment::Literal("{".to_owned()),
+                Segment::LevelLetter,
+                Segment::Literal("} ".to_owned()),
+                Segment::Msg,
+            ]
+        );
+        let err = |s: &str| s.parse::<Template>().unwrap_err().to_string();
+        assert_eq!(
+            err("a {bogus}"),
+            "template error at byte 2: unknown placeholder \"bogus\""
+        );
+        assert_eq!(
+            err("{level:2}"),
+            "template error at byte 0: unknown placeholder \"level:2\""
+        );
+        assert_eq!(
+            err("{msg"),
+            "template error at byte 0: unterminated placeholder"
+        );
+        assert_eq!(err("msg}"), "template error at byte 3: unmatched `}`");
+        assert!(err("{time:%H:%}").starts_with("template error at byte 0: invalid time format"));
+    }
+
+    #[test]
+    fn write() {
+        let kvs = [("camera", 1)];
+        let record = log::Record::builder()
+            .args(format_args!("hi"))
+            .level(log::Level::Warn)
+            .target("foo::bar")
+            .file(Some("src/bar.rs"))
+            .line(Some(42))
+            .key_values(&kvs)
+            .build();
+        assert_eq!(
+            format("{level:1}{level} {target}:{line}] {msg} {kv}", &record),
+            "Wwarn foo::bar:42] hi camera=1\n"
+        );
+        assert_eq!(format("{file}{module}", &record), "src/bar.rs\n");
+        let time = format("{time:%Y}", &record);
+        assert_eq!(time.len(), 5, "{}", time);
+    }
+}
@@ -0,0 +1,33 @@
+//! Helpers shared by the unit tests.
+
+use crate::entry_buf::{with_entry_buf, EntryBuf, Writing};
+use std::path::PathBuf;
+
+/// Returns a fresh, empty temporary directory, unique to this process and `name`.
+pub(crate) fn tempdir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mylog-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    dir
+}
+
+/// Writes an entry of at most `max_entry_size` bytes via `write`, returning it terminated.
+///
+/// Errors from `write` (i.e., truncation) are ignored; the truncated entry is still returned.
+pub(crate) fn format_bytes(
+    max_entry_size: usize,
+    write: impl FnOnce(&mut EntryBuf<Writing>) -> std::fmt::Result,
+) -> Vec<u8> {
+    with_entry_buf(max_entry_size, |mut buf| {
+        let _ = write(&mut buf);
+        buf.terminate().get().to_owned()
+    })
+}
+
+/// As `format_bytes`, for entries which are UTF-8.
+pub(crate) fn format(
+    max_entry_size: usize,
+    write: impl FnOnce(&mut EntryBuf<Writing>) -> std::fmt::Result,
+) -> String {
+    String::from_utf8(format_bytes(max_entry_size, write)).unwrap()
+}